@@ -0,0 +1,94 @@
+use crate::codec::{Body, Response, NATPMP_PORT, SUCCESS};
+use crate::netns::{self, NetnsSpec};
+use anyhow::{Context, Result};
+use log::debug;
+use socket2::{Domain, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+
+// Group and port to which gateways multicast their public address when it changes
+// (RFC 6886 3.2.1).
+pub const ANNOUNCE_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+pub const ANNOUNCE_PORT: u16 = 5350;
+
+// A public address announced by the gateway, along with its epoch. PCP gateways only
+// announce their epoch (RFC 6887 14.1.3).
+#[derive(Debug, Copy, Clone)]
+pub struct Announcement {
+    pub public_ip: Option<Ipv4Addr>,
+    pub epoch: u32,
+}
+
+// Function to subscribe to the announcements of a gateway, on the interface it is reached
+// through. Other clients on the host may listen too, so the port is shared.
+pub fn listen(gateway: Ipv4Addr, netns: Option<&NetnsSpec>) -> Result<UdpSocket> {
+    let socket = netns::enter(netns, || {
+        let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        probe.connect((gateway, NATPMP_PORT))?;
+        let interface = match probe.local_addr()? {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddrV4::new(ANNOUNCE_GROUP, ANNOUNCE_PORT).into())?;
+        socket.join_multicast_v4(&ANNOUNCE_GROUP, &interface)?;
+        socket.set_nonblocking(true)?;
+        Ok(std::net::UdpSocket::from(socket))
+    })
+    .with_context(|| {
+        format!(
+            "Failed to listen for announcements on {}:{}",
+            ANNOUNCE_GROUP, ANNOUNCE_PORT
+        )
+    })?;
+    Ok(UdpSocket::from_std(socket)?)
+}
+
+// Function to wait for the next announcement of the gateway, ignoring any other packet.
+// Without a socket, it never returns.
+pub async fn receive(socket: Option<&UdpSocket>, gateway: Ipv4Addr) -> Announcement {
+    let Some(socket) = socket else {
+        return std::future::pending().await;
+    };
+    let mut buf = [0; 64];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("Failed to receive an announcement: {}", e);
+                continue;
+            }
+        };
+        // Only the gateway may announce its address (RFC 6886 3.2.1).
+        if from.ip() != IpAddr::V4(gateway) {
+            debug!("Ignoring announcement from {}", from);
+            continue;
+        }
+        match parse(&buf[..len]) {
+            Some(announcement) => return announcement,
+            None => debug!("Ignoring invalid announcement from {}: {:02x?}", from, &buf[..len]),
+        }
+    }
+}
+
+// Function to parse a NAT-PMP public address response or a PCP ANNOUNCE response, with a
+// success result code.
+fn parse(packet: &[u8]) -> Option<Announcement> {
+    if let Ok(response) = Response::decode(packet) {
+        let Body::PublicAddress(ip) = response.body else {
+            return None;
+        };
+        return (response.result == SUCCESS).then_some(Announcement {
+            public_ip: Some(ip),
+            epoch: response.epoch,
+        });
+    }
+    match *packet {
+        [2, 128, _, 0, _, _, _, _, e0, e1, e2, e3, ..] if packet.len() >= 24 => Some(Announcement {
+            public_ip: None,
+            epoch: u32::from_be_bytes([e0, e1, e2, e3]),
+        }),
+        _ => None,
+    }
+}
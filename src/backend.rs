@@ -0,0 +1,243 @@
+use anyhow::{bail, Error, Result};
+use crate::codec::Protocol;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::Ipv4Addr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Timeouts of the successive attempts of a NAT-PMP request, doubling from 250ms to 64s
+// (RFC 6886 3.1). PCP has its own schedule.
+pub const RETRY_TIMEOUTS: [u64; 9] = [250, 500, 1000, 2000, 4000, 8000, 16000, 32000, 64000];
+
+// Timeouts of the requests probing which protocol the gateway speaks, gateways answering
+// the ones they speak right away.
+pub const PROBE_TIMEOUTS: [u64; 3] = [250, 500, 1000];
+
+// The protocol spoken to the gateway.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum BackendKind {
+    #[default]
+    NatPmp,
+    Pcp,
+    // UPnP Internet Gateway Device.
+    Upnp,
+    // PCP if the gateway speaks it, then NAT-PMP, then UPnP.
+    Auto,
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendKind::NatPmp => write!(f, "natpmp"),
+            BackendKind::Pcp => write!(f, "pcp"),
+            BackendKind::Upnp => write!(f, "upnp"),
+            BackendKind::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "natpmp" | "nat-pmp" => Ok(BackendKind::NatPmp),
+            "pcp" => Ok(BackendKind::Pcp),
+            "upnp" => Ok(BackendKind::Upnp),
+            "auto" => Ok(BackendKind::Auto),
+            _ => bail!("Invalid backend {:?}, expected natpmp, pcp, upnp or auto", s),
+        }
+    }
+}
+
+// The public address of the gateway, along with its epoch. PCP only tells the address in
+// mapping responses, so it may be unknown. UPnP has no epoch, but one which never goes
+// backwards is made up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PublicAddress {
+    pub ip: Option<Ipv4Addr>,
+    pub epoch: u32,
+}
+
+// A mapping of one protocol as granted by the gateway.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub private_port: u16,
+    pub public_port: u16,
+    pub lifetime: Duration,
+    pub epoch: u32,
+    // The public address of the mapping, which PCP gateways tell along with it.
+    pub public_ip: Option<Ipv4Addr>,
+}
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// A protocol to ask the gateway for port mappings. NAT-PMP and PCP requests run over UDP, so
+// they are sent again until the gateway replies or the retry schedule runs out.
+pub trait Backend: Send {
+    // Name of the protocol, for the logs.
+    fn name(&self) -> &'static str;
+
+    fn gateway(&self) -> Ipv4Addr;
+
+    // Query the public address and epoch of the gateway.
+    fn public_address(&mut self) -> BoxFuture<'_, Result<PublicAddress>>;
+
+    // Create, renew or, with a zero lifetime, delete the mapping of an internal port,
+    // suggesting an external port, 0 letting the gateway choose. An internal port of 0 is
+    // mapped to the same port as the public one.
+    fn map(
+        &mut self,
+        protocol: Protocol,
+        internal: u16,
+        external: u16,
+        lifetime: u32,
+    ) -> BoxFuture<'_, Result<Grant>>;
+
+    // Delete the mapping of an internal port, given its public port if known, or 0. Only
+    // UPnP needs the public port, the other protocols requesting a zero lifetime.
+    fn delete(
+        &mut self,
+        protocol: Protocol,
+        internal: u16,
+        _public: u16,
+    ) -> BoxFuture<'_, Result<Grant>> {
+        self.map(protocol, internal, 0, 0)
+    }
+}
+
+// What the clients of a gateway must remember across reconnections, which the forwarder
+// keeps while it connects again: the nonce of every PCP mapping, as (protocol, internal
+// port), which renewals and deletions must repeat (RFC 6887 11.1), the mappings which may
+// be held with a nonce this session does not know, and when the session started, from which
+// the UPnP epoch is counted so that it never goes backwards.
+#[derive(Debug, Clone)]
+pub struct Session {
+    nonces: Arc<Mutex<Nonces>>,
+    held: Arc<Mutex<HashSet<(Protocol, u16)>>>,
+    start: Instant,
+}
+
+type Nonces = HashMap<(Protocol, u16), [u8; 12]>;
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            nonces: Default::default(),
+            held: Default::default(),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Session {
+    // Seconds since the session started, the epoch of gateways which have none.
+    pub fn epoch(&self) -> u32 {
+        self.start.elapsed().as_secs() as u32
+    }
+
+    // Nonce the mapping of an internal port was granted with, if any.
+    pub fn nonce(&self, protocol: Protocol, internal: u16) -> Option<[u8; 12]> {
+        self.nonces.lock().unwrap().get(&(protocol, internal)).copied()
+    }
+
+    pub fn set_nonce(&self, protocol: Protocol, internal: u16, nonce: [u8; 12]) {
+        self.nonces.lock().unwrap().insert((protocol, internal), nonce);
+    }
+
+    pub fn remove_nonce(&self, protocol: Protocol, internal: u16) {
+        self.nonces.lock().unwrap().remove(&(protocol, internal));
+        self.held.lock().unwrap().remove(&(protocol, internal));
+    }
+
+    // Record that the gateway may hold the mapping of an internal port with another nonce,
+    // as one in the state file of a previous run or one requested without a reply.
+    pub fn set_held(&self, protocol: Protocol, internal: u16) {
+        self.held.lock().unwrap().insert((protocol, internal));
+    }
+
+    pub fn is_held(&self, protocol: Protocol, internal: u16) -> bool {
+        self.held.lock().unwrap().contains(&(protocol, internal))
+    }
+}
+
+// A result code of the gateway other than success (RFC 6886 3.5, RFC 6887 7.4), each
+// calling for its own recovery.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GatewayError {
+    // The gateway does not speak the protocol version of the request.
+    UnsupportedVersion,
+    // The gateway does not allow port mappings, such as a server without port forwarding.
+    NotAuthorized,
+    // The gateway cannot reach the outside for now, for instance having no public address.
+    NetworkFailure,
+    // The gateway has no port left, or this client has too many mappings.
+    OutOfResources,
+    UnsupportedOpcode,
+    // A result code which the protocol does not define, or which this client does not expect.
+    Undefined,
+}
+
+impl GatewayError {
+    // Whether the gateway will never forward ports, so that retrying is pointless.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            GatewayError::UnsupportedVersion
+                | GatewayError::NotAuthorized
+                | GatewayError::UnsupportedOpcode
+        )
+    }
+
+    // Get the error of the gateway behind a failure, if any.
+    pub fn find(e: &anyhow::Error) -> Option<GatewayError> {
+        e.downcast_ref().copied()
+    }
+}
+
+impl fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GatewayError::UnsupportedVersion => write!(
+                f,
+                "Gateway does not support this protocol version (UNSUPPORTEDVERSION), this server does not support port forwarding"
+            ),
+            GatewayError::NotAuthorized => write!(
+                f,
+                "Gateway refused the request (NOTAUTHORIZED), this server does not support port forwarding"
+            ),
+            GatewayError::NetworkFailure => write!(
+                f,
+                "Gateway has no network connection (NETWORKFAILURE)"
+            ),
+            GatewayError::OutOfResources => write!(
+                f,
+                "Gateway has no port left for this client (OUTOFRESOURCES)"
+            ),
+            GatewayError::UnsupportedOpcode => write!(
+                f,
+                "Gateway does not support port mapping requests (UNSUPPORTEDOPCODE), this server does not support port forwarding"
+            ),
+            GatewayError::Undefined => write!(
+                f,
+                "Gateway answered with an unknown result code (UNDEFINEDERROR)"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GatewayError {}
+
+// Function to tell whether a failure comes from the host of the gateway refusing the packets
+// of a protocol, as reported by ICMP, so that probing it is pointless.
+pub fn is_refused(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == ErrorKind::ConnectionRefused)
+}
@@ -0,0 +1,25 @@
+use std::time::Duration;
+
+// Delay before the first retry of a mapping which could not be renewed.
+pub const BACKOFF_MIN: Duration = Duration::from_secs(5);
+// Longest delay between two retries.
+pub const BACKOFF_MAX: Duration = Duration::from_secs(300);
+// Delay before retrying when the gateway has no port left, which is unlikely to change soon.
+pub const BACKOFF_LONG: Duration = Duration::from_secs(900);
+
+// Function to get the delay before the next retry after `failures` consecutive failures,
+// doubling from BACKOFF_MIN up to BACKOFF_MAX. Half of it is random, so that clients
+// which lost the gateway at the same time do not all come back at once.
+pub fn backoff(failures: u32) -> Duration {
+    let exp = failures.saturating_sub(1).min(16);
+    jitter(BACKOFF_MIN.saturating_mul(1 << exp).min(BACKOFF_MAX))
+}
+
+// Function to get the delay before retrying after the gateway ran out of resources.
+pub fn long_backoff() -> Duration {
+    jitter(BACKOFF_LONG)
+}
+
+fn jitter(delay: Duration) -> Duration {
+    delay / 2 + delay.mul_f64(fastrand::f64() / 2.0)
+}
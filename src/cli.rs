@@ -0,0 +1,220 @@
+use natpmp_setup::backend::BackendKind;
+use natpmp_setup::config::{HookOverrides, Overrides};
+use natpmp_setup::gateway::GatewaySpec;
+use natpmp_setup::mapping::{MappingSpec, Protocols};
+use natpmp_setup::netns::NetnsSpec;
+use natpmp_setup::output::OutputSpec;
+use natpmp_setup::renewal::{parse_fraction, RenewAt};
+use clap::{Args, Parser, Subcommand};
+use log::LevelFilter;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// NAT-PMP port forwarding daemon for ProtonVPN and other NAT-PMP gateways.
+///
+/// Settings are taken from command line flags first, then environment variables,
+/// then the configuration file, then defaults.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML configuration file.
+    #[arg(short, long, env = "NATPMP_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// NAT-PMP gateway: an IPv4 address, `auto` for the gateway of the default route, or
+    /// `auto:INTERFACE` for the gateway of the routes of an interface [default: 10.2.0.1].
+    #[arg(long, value_name = "GATEWAY", env = "NATPMP_GATEWAY_IP", global = true)]
+    pub gateway: Option<GatewaySpec>,
+
+    /// Protocol spoken to the gateway: natpmp, pcp (Port Control Protocol), upnp (UPnP
+    /// Internet Gateway Device), or auto for the first of PCP, NAT-PMP and UPnP which the
+    /// gateway answers [default: natpmp].
+    #[arg(long, value_name = "BACKEND", env = "NATPMP_BACKEND", global = true)]
+    pub backend: Option<BackendKind>,
+
+    /// Tunnel of the configuration file to use, instead of all of them.
+    #[arg(long, env = "NATPMP_TUNNEL", global = true)]
+    pub tunnel: Option<String>,
+
+    /// Network namespace to talk to the gateway from, as a name under /run/netns or a path
+    /// such as /proc/PID/ns/net. Files and hooks are still those of the host.
+    #[arg(long, value_name = "NETNS", env = "NATPMP_NETNS", global = true)]
+    pub netns: Option<NetnsSpec>,
+
+    /// Verbosity of the logs: off, error, warn, info, debug or trace [default: info].
+    #[arg(long, env = "NATPMP_LOG_LEVEL", global = true)]
+    pub log_level: Option<LevelFilter>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Keep the mappings alive until SIGTERM/SIGINT, writing their ports to a file.
+    Run(Box<RunArgs>),
+    /// Create the mappings once, print their ports and exit.
+    Once(MappingArgs),
+    /// Delete a mapping from the gateway.
+    Release(ReleaseArgs),
+    /// Print the public IP address of the gateway.
+    PublicIp,
+    /// Print the mappings currently maintained by a running daemon.
+    Status(OutputArgs),
+    /// Validate the configuration file and print the resulting settings.
+    CheckConfig,
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub output: OutputArgs,
+
+    /// File where every port change is appended as a `Name,PID,TCPPort,UDPPort,PublicIP` line.
+    #[arg(long, env = "NATPMP_HISTORY")]
+    pub history: Option<PathBuf>,
+
+    /// File where the ports are saved, so that a restart asks the gateway for the same ones.
+    #[arg(long, env = "NATPMP_STATE_FILE")]
+    pub state_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub mappings: MappingArgs,
+
+    /// Seconds between two queries of the public IP address [default: 300].
+    #[arg(long, env = "NATPMP_PUBLIC_IP_INTERVAL", value_name = "SECONDS", value_parser = parse_seconds)]
+    pub public_ip_interval: Option<Duration>,
+
+    /// When to renew a mapping: a fraction of its granted lifetime such as 0.5 or 50%, or a
+    /// margin before it expires such as 30s [default: 0.5].
+    #[arg(long, env = "NATPMP_RENEW_AT", value_name = "WHEN")]
+    pub renew_at: Option<RenewAt>,
+
+    /// Fraction of the renewal delay, such as 0.1 or 10%, by which renewals are randomly
+    /// moved earlier [default: 0].
+    #[arg(long, env = "NATPMP_RENEW_JITTER", value_name = "FRACTION", value_parser = parse_jitter)]
+    pub renew_jitter: Option<f64>,
+
+    #[command(flatten)]
+    pub hooks: HookArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct HookArgs {
+    /// Shell command to run when a mapping is created.
+    #[arg(long, env = "NATPMP_HOOK_CREATED")]
+    pub hook_created: Option<String>,
+
+    /// Shell command to run when the public port of a mapping changes.
+    #[arg(long, env = "NATPMP_HOOK_PORT_CHANGED")]
+    pub hook_port_changed: Option<String>,
+
+    /// Shell command to run when the public IP address of the gateway changes.
+    #[arg(long, env = "NATPMP_HOOK_PUBLIC_IP_CHANGED")]
+    pub hook_public_ip_changed: Option<String>,
+
+    /// Shell command to run when a mapping could not be renewed.
+    #[arg(long, env = "NATPMP_HOOK_LOST")]
+    pub hook_lost: Option<String>,
+
+    /// Seconds a hook command may run before it is killed [default: 30].
+    #[arg(long, env = "NATPMP_HOOK_TIMEOUT", value_name = "SECONDS", value_parser = parse_seconds)]
+    pub hook_timeout: Option<Duration>,
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    match s.parse() {
+        Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("{:?} is not a positive number of seconds", s)),
+    }
+}
+
+fn parse_jitter(s: &str) -> Result<f64, String> {
+    parse_fraction(s).map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Args)]
+pub struct OutputArgs {
+    /// File where the daemon keeps the current ports, as `[FORMAT:]PATH` (repeatable).
+    ///
+    /// FORMAT is plain (default, `Name,PID,TCPPort,UDPPort,PublicIP` lines), csv (with a
+    /// header and a timestamp), json, env (shell-sourceable) or port (the bare port number).
+    #[arg(
+        short,
+        long = "output",
+        value_name = "OUTPUT",
+        env = "NATPMP_OUTPUT",
+        value_delimiter = ','
+    )]
+    pub outputs: Vec<OutputSpec>,
+}
+
+#[derive(Debug, Args)]
+pub struct MappingArgs {
+    /// Mapping to create, as `NAME[:PROTOCOL[:INTERNAL_PORT[:LIFETIME[:PUBLIC_PORT[:POLICY]]]]]`
+    /// (repeatable) [default: default].
+    ///
+    /// PUBLIC_PORT is a preferred port or a FIRST-LAST range of allowed ports, and POLICY
+    /// what to do when the gateway grants another one: accept, retry or fail.
+    #[arg(
+        short,
+        long = "mapping",
+        value_name = "MAPPING",
+        env = "NATPMP_MAPPINGS",
+        value_delimiter = ','
+    )]
+    pub mappings: Vec<MappingSpec>,
+}
+
+#[derive(Debug, Args)]
+pub struct ReleaseArgs {
+    /// Protocol of the mapping to delete: tcp, udp or both.
+    #[arg(short, long, env = "NATPMP_PROTOCOL", default_value = "both")]
+    pub protocol: Protocols,
+
+    /// Internal port of the mapping to delete, 0 deletes every mapping of this host (with
+    /// UPnP, every one made by natpmp-setup).
+    #[arg(short, long, env = "NATPMP_INTERNAL_PORT")]
+    pub internal_port: u16,
+
+    /// State file of the daemon, whose nonces PCP gateways require to delete its mappings.
+    #[arg(long, env = "NATPMP_STATE_FILE")]
+    pub state_file: Option<PathBuf>,
+}
+
+impl Cli {
+    // Settings given through flags or environment variables, to apply over the configuration file.
+    pub fn overrides(&self) -> Overrides {
+        let mut overrides = Overrides {
+            tunnel: self.tunnel.clone(),
+            gateway: self.gateway.clone(),
+            backend: self.backend,
+            netns: self.netns.clone(),
+            log_level: self.log_level,
+            ..Default::default()
+        };
+        match &self.command {
+            Command::Run(args) => {
+                overrides.outputs = args.output.outputs.clone();
+                overrides.history = args.history.clone();
+                overrides.mappings = args.mappings.mappings.clone();
+                overrides.public_ip_interval = args.public_ip_interval;
+                overrides.state_file = args.state_file.clone();
+                overrides.renew_at = args.renew_at;
+                overrides.renew_jitter = args.renew_jitter;
+                overrides.hooks = HookOverrides {
+                    created: args.hooks.hook_created.clone(),
+                    port_changed: args.hooks.hook_port_changed.clone(),
+                    public_ip_changed: args.hooks.hook_public_ip_changed.clone(),
+                    lost: args.hooks.hook_lost.clone(),
+                    timeout: args.hooks.hook_timeout,
+                };
+            }
+            Command::Once(args) => overrides.mappings = args.mappings.clone(),
+            Command::Status(args) => overrides.outputs = args.outputs.clone(),
+            Command::Release(args) => overrides.state_file = args.state_file.clone(),
+            Command::PublicIp | Command::CheckConfig => {}
+        }
+        overrides
+    }
+}
@@ -0,0 +1,321 @@
+use crate::backend::{Backend, BackendKind, BoxFuture, Grant, PublicAddress, Session};
+use crate::codec::{Protocol, NATPMP_PORT};
+use crate::gateway::GatewaySpec;
+use crate::mapping::{Mapping, MappingSpec, PortPolicy, Protocols};
+use crate::netns::{self, NetnsSpec};
+use crate::pcp::Pcp;
+use crate::pmp::NatPmp;
+use crate::upnp::Igd;
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use tokio::net::UdpSocket;
+
+// Client of the gateway, speaking the protocol it was negotiated with.
+pub type Client = Box<dyn Backend>;
+
+// Function to create a client for the given gateway, speaking the given protocol. The
+// gateway is looked up and the sockets created in the network namespace, if any. Clients
+// connected with the same session carry on with the mappings of the previous ones.
+pub async fn connect(
+    gateway: &GatewaySpec,
+    netns: Option<&NetnsSpec>,
+    backend: BackendKind,
+    session: &Session,
+) -> Result<Client> {
+    let (gateway, socket) = netns::enter(netns, || {
+        let gateway = gateway.resolve()?;
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|s| s.connect((gateway, NATPMP_PORT)).map(|_| s))
+            .with_context(|| format!("Failed to connect to gateway {}", gateway))?;
+        Ok((gateway, socket))
+    })?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    match backend {
+        BackendKind::NatPmp => Ok(Box::new(NatPmp::new(socket, gateway))),
+        BackendKind::Pcp => Ok(Box::new(Pcp::new(socket, gateway, session.clone())?)),
+        BackendKind::Upnp => match Igd::discover(gateway, netns, session).await? {
+            Some(igd) => Ok(Box::new(igd)),
+            None => bail!("Gateway {} does not answer UPnP searches", gateway),
+        },
+        BackendKind::Auto => negotiate(gateway, netns, socket, session).await,
+    }
+}
+
+// Client of a gateway which could not be connected to, as while the VPN starts, failing
+// every request until the forwarder connects again.
+struct Unconnected {
+    gateway: GatewaySpec,
+}
+
+impl Backend for Unconnected {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::UNSPECIFIED
+    }
+
+    fn public_address(&mut self) -> BoxFuture<'_, Result<PublicAddress>> {
+        Box::pin(async { bail!("Gateway {} is not connected", self.gateway) })
+    }
+
+    fn map(&mut self, _: Protocol, _: u16, _: u16, _: u32) -> BoxFuture<'_, Result<Grant>> {
+        Box::pin(async { bail!("Gateway {} is not connected", self.gateway) })
+    }
+}
+
+// Function to get a client for a gateway which could not be connected to yet.
+pub(crate) fn unconnected(gateway: &GatewaySpec) -> Client {
+    Box::new(Unconnected {
+        gateway: gateway.clone(),
+    })
+}
+
+// Function to find the protocol the gateway speaks, trying PCP, then NAT-PMP, then UPnP. A
+// gateway which answers none of them is spoken NAT-PMP, in case it is only slow.
+async fn negotiate(
+    gateway: Ipv4Addr,
+    netns: Option<&NetnsSpec>,
+    socket: UdpSocket,
+    session: &Session,
+) -> Result<Client> {
+    let mut pcp = Pcp::new(socket, gateway, session.clone())?;
+    if pcp.probe().await? {
+        info!("Gateway {} speaks PCP", gateway);
+        return Ok(Box::new(pcp));
+    }
+    let pmp = NatPmp::new(pcp.into_socket(), gateway);
+    if pmp.probe().await {
+        info!("Gateway {} speaks NAT-PMP", gateway);
+        return Ok(Box::new(pmp));
+    }
+    if let Some(igd) = Igd::discover(gateway, netns, session).await? {
+        info!("Gateway {} speaks UPnP", gateway);
+        return Ok(Box::new(igd));
+    }
+    info!("Gateway {} answers no protocol, falling back to NAT-PMP", gateway);
+    Ok(Box::new(pmp))
+}
+
+// Function to query the gateway for a public IP address.
+pub async fn query_gateway(n: &mut Client) -> Result<PublicAddress> {
+    n.public_address().await
+}
+
+// Number of ports suggested to the gateway with the retry policy before giving up.
+const MAX_PORT_ATTEMPTS: usize = 8;
+
+// The public port asked for a mapping, and what to do when the gateway grants another one.
+#[derive(Debug, Clone)]
+pub struct PortRequest {
+    // Port suggested to the gateway, 0 letting it choose.
+    pub external: u16,
+    // Public ports which may be granted.
+    pub allowed: RangeInclusive<u16>,
+    pub policy: PortPolicy,
+}
+
+impl PortRequest {
+    // Any port the gateway chooses.
+    pub fn any() -> PortRequest {
+        PortRequest {
+            external: 0,
+            allowed: 1..=u16::MAX,
+            policy: PortPolicy::Accept,
+        }
+    }
+
+    // Exactly the given port, as when renewing a mapping.
+    pub fn exact(port: u16) -> PortRequest {
+        PortRequest {
+            external: port,
+            allowed: port..=port,
+            policy: PortPolicy::Fail,
+        }
+    }
+
+    // The public ports declared for a mapping.
+    pub fn for_spec(spec: &MappingSpec) -> PortRequest {
+        let allowed = match (&spec.public_ports, spec.public_port) {
+            (Some(range), _) => range.clone(),
+            (None, 0) => 1..=u16::MAX,
+            (None, port) => port..=port,
+        };
+        PortRequest {
+            external: spec.preferred_port(),
+            allowed,
+            policy: spec.policy,
+        }
+    }
+
+    // Next port to suggest, the allowed ones following the first suggestion coming first.
+    fn next(&self, tried: &[u16]) -> Option<u16> {
+        let after = self.allowed.clone().filter(|&p| p > self.external);
+        let before = self.allowed.clone().filter(|&p| p < self.external);
+        after.chain(before).find(|p| !tried.contains(p))
+    }
+}
+
+// Failure of a mapping whose policy refuses the public ports the gateway grants.
+#[derive(Debug)]
+pub struct PortRefused(String);
+
+impl fmt::Display for PortRefused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for PortRefused {}
+
+// Function to query available ports for a mapping, sharing the public port if possible.
+pub async fn query_available_mapping(n: &mut Client, spec: MappingSpec) -> Result<Mapping> {
+    let (internal, lifetime) = (spec.internal_port, spec.lifetime);
+    let request = PortRequest::for_spec(&spec);
+    match spec.protocols {
+        Protocols::Tcp => {
+            let tcp = query_port(n, Protocol::TCP, internal, lifetime, &request).await?;
+            return Ok(Mapping::new(spec, Some(tcp), None));
+        }
+        Protocols::Udp => {
+            let udp = query_port(n, Protocol::UDP, internal, lifetime, &request).await?;
+            return Ok(Mapping::new(spec, None, Some(udp)));
+        }
+        Protocols::Both => {}
+    }
+
+    let mut tcp = query_port(n, Protocol::TCP, internal, lifetime, &request).await?;
+    // Ask for the UDP mapping on the same ports as the TCP one.
+    let request = PortRequest {
+        external: tcp.public_port,
+        ..request
+    };
+    let udp = query_port(n, Protocol::UDP, tcp.private_port, lifetime, &request).await?;
+    if tcp.public_port != udp.public_port {
+        // The gateway picked another port for UDP, try to move TCP onto it instead.
+        warn!(
+            "Gateway gave UDP port {} instead of {} for {}, trying to align TCP...",
+            udp.public_port,
+            tcp.public_port,
+            spec.name
+        );
+        let request = PortRequest::exact(udp.public_port);
+        tcp = query_port(n, Protocol::TCP, udp.private_port, lifetime, &request)
+            .await
+            .unwrap_or(tcp);
+    }
+    Ok(Mapping::new(spec, Some(tcp), Some(udp)))
+}
+
+// Function to ask again for the ports a mapping had in a previous run, as (protocol, private,
+// public) for every protocol, failing unless the gateway grants them as they were.
+pub async fn restore_mapping(
+    n: &mut Client,
+    spec: MappingSpec,
+    ports: &[(Protocol, u16, u16)],
+) -> Result<Mapping> {
+    let (mut tcp, mut udp) = (None, None);
+    for protocol in spec.protocols.iter() {
+        let Some(&(_, private, public)) = ports.iter().find(|(p, _, _)| *p == protocol) else {
+            bail!("No {:?} port was saved", protocol);
+        };
+        let request = PortRequest::exact(public);
+        let mr = query_port(n, protocol, private, spec.lifetime, &request).await?;
+        match protocol {
+            Protocol::TCP => tcp = Some(mr),
+            Protocol::UDP => udp = Some(mr),
+        }
+    }
+    Ok(Mapping::new(spec, tcp, udp))
+}
+
+// Function to renew every protocol of a mapping on its current ports.
+pub async fn renew_mapping(n: &mut Client, m: &Mapping) -> Result<Mapping> {
+    let (mut tcp, mut udp) = (None, None);
+    for protocol in [Protocol::TCP, Protocol::UDP] {
+        let Some(mr) = m.get(protocol) else {
+            continue;
+        };
+        let request = PortRequest::exact(mr.public_port);
+        let mr = query_port(n, protocol, mr.private_port, m.spec.lifetime, &request).await?;
+        match protocol {
+            Protocol::TCP => tcp = Some(mr),
+            Protocol::UDP => udp = Some(mr),
+        }
+    }
+    Ok(Mapping::new(m.spec.clone(), tcp, udp))
+}
+
+// Function to delete every protocol of a mapping, by requesting a zero lifetime (RFC 6886 3.4).
+pub async fn release_mapping(n: &mut Client, m: &Mapping) -> Result<()> {
+    for protocol in [Protocol::TCP, Protocol::UDP] {
+        let Some(mr) = m.get(protocol) else {
+            continue;
+        };
+        release_port(n, protocol, mr.private_port, mr.public_port).await?;
+        info!("Released {:?} port {} of {}", protocol, mr.public_port, m.spec.name);
+    }
+    Ok(())
+}
+
+// Function to delete the mapping of an internal port, given its public port if known, or 0.
+pub async fn release_port(
+    n: &mut Client,
+    protocol: Protocol,
+    internal: u16,
+    public: u16,
+) -> Result<()> {
+    n.delete(protocol, internal, public).await?;
+    Ok(())
+}
+
+// Function to request or renew a port mapping, applying the policy of the request when the
+// gateway grants another public port than the allowed ones.
+pub async fn query_port(
+    n: &mut Client,
+    protocol: Protocol,
+    internal: u16,
+    lifetime: u32,
+    request: &PortRequest,
+) -> Result<Grant> {
+    let mut external = request.external;
+    let mut tried = vec![];
+    loop {
+        let mr = n.map(protocol, internal, external, lifetime).await?;
+        let granted = mr.public_port;
+        if request.allowed.contains(&granted) && mr.lifetime.as_secs() > 0 {
+            return Ok(mr);
+        }
+        let refused = format!(
+            "Gateway granted {:?} port {} for {}s instead of a port in {}-{}",
+            protocol,
+            granted,
+            mr.lifetime.as_secs(),
+            request.allowed.start(),
+            request.allowed.end()
+        );
+        if request.policy == PortPolicy::Accept {
+            warn!("{}, accepting it", refused);
+            return Ok(mr);
+        }
+        // Give the port back, the next request would otherwise get it again.
+        if let Err(e) = release_port(n, protocol, mr.private_port, granted).await {
+            warn!("Failed to release {:?} port {}: {}", protocol, granted, e);
+        }
+        tried.push(external);
+        let next = match request.policy {
+            PortPolicy::Retry if tried.len() < MAX_PORT_ATTEMPTS => request.next(&tried),
+            _ => None,
+        };
+        let Some(next) = next else {
+            return Err(PortRefused(refused).into());
+        };
+        info!("{}, asking for port {}...", refused, next);
+        external = next;
+    }
+}
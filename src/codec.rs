@@ -0,0 +1,270 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+
+// Port on which NAT-PMP gateways listen (RFC 6886 3.1).
+pub const NATPMP_PORT: u16 = 5351;
+
+// Version of the protocol, PCP being version 2 (RFC 6886 3).
+const VERSION: u8 = 0;
+// Bit of the opcode set in responses.
+const RESPONSE: u8 = 0x80;
+// Length of the header of responses: version, opcode, result code and epoch.
+const HEADER_LEN: usize = 8;
+const PUBLIC_ADDRESS_REQUEST_LEN: usize = 2;
+const MAP_REQUEST_LEN: usize = 12;
+const PUBLIC_ADDRESS_RESPONSE_LEN: usize = 12;
+const MAP_RESPONSE_LEN: usize = 16;
+
+// Result code of a successful request (RFC 6886 3.5).
+pub const SUCCESS: u16 = 0;
+
+// Transport protocol of a mapping.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Protocol {
+    UDP,
+    TCP,
+}
+
+// Operation of a request, which its response repeats (RFC 6886 3.2, 3.3).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Opcode {
+    PublicAddress,
+    Map(Protocol),
+}
+
+impl Opcode {
+    fn to_byte(self) -> u8 {
+        match self {
+            Opcode::PublicAddress => 0,
+            Opcode::Map(Protocol::UDP) => 1,
+            Opcode::Map(Protocol::TCP) => 2,
+        }
+    }
+
+    fn from_byte(opcode: u8) -> Option<Opcode> {
+        match opcode {
+            0 => Some(Opcode::PublicAddress),
+            1 => Some(Opcode::Map(Protocol::UDP)),
+            2 => Some(Opcode::Map(Protocol::TCP)),
+            _ => None,
+        }
+    }
+}
+
+// A request of a client to the gateway.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Request {
+    PublicAddress,
+    // Create, renew or, with a zero lifetime, delete the mapping of an internal port,
+    // suggesting an external port, 0 letting the gateway choose (RFC 6886 3.3).
+    Map {
+        protocol: Protocol,
+        internal: u16,
+        external: u16,
+        lifetime: u32,
+    },
+}
+
+// A response of the gateway. Responses with a result code other than success may stop
+// after the epoch, the following fields being meaningless anyway (RFC 6886 3.5).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Response {
+    pub opcode: Opcode,
+    pub result: u16,
+    // Seconds since the gateway started or lost its mappings.
+    pub epoch: u32,
+    pub body: Body,
+}
+
+// The fields of a response following the epoch.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Body {
+    Empty,
+    PublicAddress(Ipv4Addr),
+    Map {
+        internal: u16,
+        external: u16,
+        lifetime: u32,
+    },
+}
+
+// Why a packet is not a valid NAT-PMP message.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    // The packet is too short to tell its version and opcode.
+    Truncated,
+    // The packet is of another version, such as a PCP response.
+    Version(u8),
+    // A request was expected and the packet is a response, or the other way around.
+    Direction,
+    Opcode(u8),
+    // The packet is not as long as its opcode and result code require.
+    Length { opcode: u8, len: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "NAT-PMP packet is truncated"),
+            DecodeError::Version(version) => {
+                write!(f, "NAT-PMP packet has version {}, expected 0", version)
+            }
+            DecodeError::Direction => write!(f, "NAT-PMP packet goes the other way"),
+            DecodeError::Opcode(opcode) => {
+                write!(f, "NAT-PMP packet has unknown opcode {}", opcode)
+            }
+            DecodeError::Length { opcode, len } => write!(
+                f,
+                "NAT-PMP packet of opcode {} has invalid length {}",
+                opcode, len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Request {
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Request::PublicAddress => Opcode::PublicAddress,
+            Request::Map { protocol, .. } => Opcode::Map(*protocol),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = vec![VERSION, self.opcode().to_byte()];
+        if let Request::Map {
+            internal,
+            external,
+            lifetime,
+            ..
+        } = self
+        {
+            // Reserved field (RFC 6886 3.3).
+            packet.extend([0, 0]);
+            packet.extend(internal.to_be_bytes());
+            packet.extend(external.to_be_bytes());
+            packet.extend(lifetime.to_be_bytes());
+        }
+        packet
+    }
+
+    // Decode a request, the reserved field being ignored as the gateway does.
+    pub fn decode(packet: &[u8]) -> Result<Request, DecodeError> {
+        let (opcode, code) = header(packet, false)?;
+        let expected = match opcode {
+            Opcode::PublicAddress => PUBLIC_ADDRESS_REQUEST_LEN,
+            Opcode::Map(_) => MAP_REQUEST_LEN,
+        };
+        if packet.len() != expected {
+            return Err(DecodeError::Length {
+                opcode: code,
+                len: packet.len(),
+            });
+        }
+        Ok(match opcode {
+            Opcode::PublicAddress => Request::PublicAddress,
+            Opcode::Map(protocol) => Request::Map {
+                protocol,
+                internal: u16_at(packet, 4),
+                external: u16_at(packet, 6),
+                lifetime: u32_at(packet, 8),
+            },
+        })
+    }
+
+    // Whether a response answers this request: it must have the same opcode and, for a
+    // mapping, the same internal port. A mapping of internal port 0 may be answered for
+    // any port, as ProtonVPN maps it to the public one, and so may error responses which
+    // stop after the epoch.
+    pub fn matches(&self, response: &Response) -> bool {
+        if self.opcode() != response.opcode {
+            return false;
+        }
+        match (self, response.body) {
+            (Request::Map { internal, .. }, Body::Map { internal: port, .. }) => {
+                *internal == 0 || *internal == port
+            }
+            _ => true,
+        }
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = vec![VERSION, RESPONSE | self.opcode.to_byte()];
+        packet.extend(self.result.to_be_bytes());
+        packet.extend(self.epoch.to_be_bytes());
+        match self.body {
+            Body::Empty => {}
+            Body::PublicAddress(ip) => packet.extend(ip.octets()),
+            Body::Map {
+                internal,
+                external,
+                lifetime,
+            } => {
+                packet.extend(internal.to_be_bytes());
+                packet.extend(external.to_be_bytes());
+                packet.extend(lifetime.to_be_bytes());
+            }
+        }
+        packet
+    }
+
+    // Decode a response. A successful one must carry every field of its opcode, and no more.
+    pub fn decode(packet: &[u8]) -> Result<Response, DecodeError> {
+        let (opcode, code) = header(packet, true)?;
+        let invalid = DecodeError::Length {
+            opcode: code,
+            len: packet.len(),
+        };
+        if packet.len() < HEADER_LEN {
+            return Err(invalid);
+        }
+        let result = u16_at(packet, 2);
+        let body = match (opcode, packet.len()) {
+            (_, HEADER_LEN) if result != SUCCESS => Body::Empty,
+            (Opcode::PublicAddress, PUBLIC_ADDRESS_RESPONSE_LEN) => {
+                Body::PublicAddress(Ipv4Addr::from(u32_at(packet, 8)))
+            }
+            (Opcode::Map(_), MAP_RESPONSE_LEN) => Body::Map {
+                internal: u16_at(packet, 8),
+                external: u16_at(packet, 10),
+                lifetime: u32_at(packet, 12),
+            },
+            _ => return Err(invalid),
+        };
+        Ok(Response {
+            opcode,
+            result,
+            epoch: u32_at(packet, 4),
+            body,
+        })
+    }
+}
+
+// Function to check the version and direction of a packet, returning its opcode, both
+// decoded and as sent.
+fn header(packet: &[u8], response: bool) -> Result<(Opcode, u8), DecodeError> {
+    let [version, opcode, ..] = *packet else {
+        return Err(DecodeError::Truncated);
+    };
+    if version != VERSION {
+        return Err(DecodeError::Version(version));
+    }
+    if (opcode & RESPONSE != 0) != response {
+        return Err(DecodeError::Direction);
+    }
+    let code = opcode & !RESPONSE;
+    let opcode = Opcode::from_byte(code).ok_or(DecodeError::Opcode(code))?;
+    Ok((opcode, code))
+}
+
+fn u16_at(packet: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([packet[at], packet[at + 1]])
+}
+
+fn u32_at(packet: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(packet[at..at + 4].try_into().unwrap())
+}
@@ -0,0 +1,540 @@
+use crate::backend::BackendKind;
+use crate::gateway::GatewaySpec;
+use crate::hooks::{HookCommands, DEFAULT_HOOK_TIMEOUT};
+use crate::mapping::{parse_port_range, MappingSpec, PortPolicy, Protocols, DEFAULT_LIFETIME};
+use crate::netns::NetnsSpec;
+use crate::output::OutputSpec;
+use crate::renewal::{RenewAt, Renewal};
+use anyhow::{anyhow, bail, Context, Result};
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use toml::Spanned;
+
+// Gateway used when neither the command line, the environment nor the file set one.
+pub const DEFAULT_GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 2, 0, 1);
+
+// Time between two queries of the public address, when not configured.
+pub const DEFAULT_PUBLIC_IP_INTERVAL: Duration = Duration::from_secs(300);
+
+// Settings of the daemon, once the command line, environment and file are merged. A file
+// declaring several tunnels gives one per tunnel, each kept alive by its own forwarder.
+#[derive(Debug, Clone)]
+pub struct Config {
+    // Name of the tunnel, if the configuration file declares them.
+    pub tunnel: Option<String>,
+    pub gateway: GatewaySpec,
+    // Protocol spoken to the gateway.
+    pub backend: BackendKind,
+    pub netns: Option<NetnsSpec>,
+    pub outputs: Vec<OutputSpec>,
+    pub history: Option<PathBuf>,
+    // File where the ports are saved, to get them back after a restart.
+    pub state_file: Option<PathBuf>,
+    pub mappings: Vec<MappingSpec>,
+    pub public_ip_interval: Duration,
+    pub renewal: Renewal,
+    pub hooks: HookCommands,
+    pub log_level: LevelFilter,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            tunnel: None,
+            gateway: GatewaySpec::default(),
+            backend: BackendKind::default(),
+            netns: None,
+            outputs: vec![],
+            history: None,
+            state_file: None,
+            mappings: vec![MappingSpec::default()],
+            public_ip_interval: DEFAULT_PUBLIC_IP_INTERVAL,
+            renewal: Renewal::default(),
+            hooks: Default::default(),
+            log_level: LevelFilter::Info,
+        }
+    }
+}
+
+// Settings given on the command line or in the environment, which override the file.
+#[derive(Debug, Default)]
+pub struct Overrides {
+    // Only keep this tunnel of the configuration file.
+    pub tunnel: Option<String>,
+    pub gateway: Option<GatewaySpec>,
+    pub backend: Option<BackendKind>,
+    pub netns: Option<NetnsSpec>,
+    pub outputs: Vec<OutputSpec>,
+    pub history: Option<PathBuf>,
+    pub state_file: Option<PathBuf>,
+    pub mappings: Vec<MappingSpec>,
+    pub public_ip_interval: Option<Duration>,
+    pub renew_at: Option<RenewAt>,
+    pub renew_jitter: Option<f64>,
+    pub hooks: HookOverrides,
+    pub log_level: Option<LevelFilter>,
+}
+
+#[derive(Debug, Default)]
+pub struct HookOverrides {
+    pub created: Option<String>,
+    pub port_changed: Option<String>,
+    pub public_ip_changed: Option<String>,
+    pub lost: Option<String>,
+    pub timeout: Option<Duration>,
+}
+
+// Contents of the TOML configuration file, every setting being optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    gateway: Option<GatewaySpec>,
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    backend: Option<BackendKind>,
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    netns: Option<NetnsSpec>,
+    // A single output or an array of them.
+    output: Option<Spanned<toml::Value>>,
+    history: Option<PathBuf>,
+    state_file: Option<PathBuf>,
+    #[serde(default, rename = "mapping")]
+    mappings: Vec<FileMapping>,
+    #[serde(default, rename = "tunnel")]
+    tunnels: Vec<FileTunnel>,
+    public_ip_interval: Option<Spanned<u64>>,
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    renew_at: Option<RenewAt>,
+    renew_jitter: Option<Spanned<f64>>,
+    #[serde(default)]
+    hooks: FileHooks,
+    #[serde(default)]
+    log: FileLog,
+}
+
+// A tunnel, whose settings default to those of the top level.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTunnel {
+    name: Spanned<String>,
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    gateway: Option<GatewaySpec>,
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    backend: Option<BackendKind>,
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    netns: Option<NetnsSpec>,
+    output: Option<Spanned<toml::Value>>,
+    history: Option<PathBuf>,
+    state_file: Option<PathBuf>,
+    #[serde(default, rename = "mapping")]
+    mappings: Vec<FileMapping>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileMapping {
+    name: Spanned<String>,
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    protocol: Option<Protocols>,
+    #[serde(default)]
+    internal_port: u16,
+    lifetime: Option<Spanned<u32>>,
+    public_port: Option<Spanned<u16>>,
+    public_ports: Option<Spanned<String>>,
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    port_policy: Option<PortPolicy>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileHooks {
+    created: Option<String>,
+    port_changed: Option<String>,
+    public_ip_changed: Option<String>,
+    lost: Option<String>,
+    timeout: Option<Spanned<u64>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileLog {
+    level: Option<Spanned<String>>,
+}
+
+// Function to deserialize a string setting with the `FromStr` implementation of its type.
+fn deserialize_parsed<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = anyhow::Error>,
+{
+    let s = String::deserialize(d)?;
+    s.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+impl Config {
+    // Build the configuration of every tunnel, or a single one if the file declares none.
+    // Command line flags and environment variables take precedence over the configuration
+    // file, where the settings of a tunnel take precedence over the top level ones, which
+    // take precedence over defaults. Flags set for several tunnels must not replace
+    // settings of their own.
+    pub fn load(path: Option<&Path>, overrides: Overrides) -> Result<Vec<Config>> {
+        let file = match path {
+            Some(path) => read_file(path)?,
+            None => Default::default(),
+        };
+
+        let outputs = if !overrides.outputs.is_empty() {
+            overrides.outputs.clone()
+        } else {
+            file.outputs
+        };
+        let mappings = if !overrides.mappings.is_empty() {
+            overrides.mappings.clone()
+        } else if !file.mappings.is_empty() {
+            file.mappings
+        } else {
+            vec![MappingSpec::default()]
+        };
+
+        let base = Config {
+            tunnel: None,
+            gateway: overrides.gateway.clone().or(file.gateway).unwrap_or_default(),
+            backend: overrides.backend.or(file.backend).unwrap_or_default(),
+            netns: overrides.netns.clone().or(file.netns),
+            outputs,
+            history: overrides.history.clone().or(file.history),
+            state_file: overrides.state_file.clone().or(file.state_file),
+            mappings,
+            public_ip_interval: overrides
+                .public_ip_interval
+                .or(file.public_ip_interval)
+                .unwrap_or(DEFAULT_PUBLIC_IP_INTERVAL),
+            renewal: Renewal {
+                at: overrides.renew_at.or(file.renew_at).unwrap_or_default(),
+                jitter: overrides.renew_jitter.or(file.renew_jitter).unwrap_or_default(),
+            },
+            hooks: HookCommands {
+                created: overrides.hooks.created.or(file.hooks.created),
+                port_changed: overrides.hooks.port_changed.or(file.hooks.port_changed),
+                public_ip_changed: overrides
+                    .hooks
+                    .public_ip_changed
+                    .or(file.hooks.public_ip_changed),
+                lost: overrides.hooks.lost.or(file.hooks.lost),
+                timeout: overrides
+                    .hooks
+                    .timeout
+                    .or(file.hooks.timeout)
+                    .unwrap_or(DEFAULT_HOOK_TIMEOUT),
+            },
+            log_level: overrides.log_level.or(file.log_level).unwrap_or(LevelFilter::Info),
+        };
+        if file.tunnels.is_empty() {
+            if let Some(tunnel) = overrides.tunnel {
+                bail!("Cannot select tunnel {}, the configuration file declares none", tunnel);
+            }
+            return Ok(vec![base]);
+        }
+
+        let several = file.tunnels.len() > 1;
+        let mut configs = Vec::with_capacity(file.tunnels.len());
+        for t in file.tunnels {
+            // Flags and environment variables win over the settings of the selected tunnel
+            // too, but cannot replace those of each of several tunnels.
+            let replace = match &overrides.tunnel {
+                Some(tunnel) => *tunnel == t.name,
+                None => !several,
+            };
+            let conflicts = [
+                ("--gateway", overrides.gateway.is_some() && t.gateway.is_some()),
+                ("--backend", overrides.backend.is_some() && t.backend.is_some()),
+                ("--netns", overrides.netns.is_some() && t.netns.is_some()),
+                ("--output", !overrides.outputs.is_empty() && !t.outputs.is_empty()),
+                ("--history", overrides.history.is_some() && t.history.is_some()),
+                ("--state-file", overrides.state_file.is_some() && t.state_file.is_some()),
+                ("--mapping", !overrides.mappings.is_empty() && !t.mappings.is_empty()),
+            ];
+            let flags: Vec<_> = conflicts.iter().filter(|c| c.1).map(|c| c.0).collect();
+            if overrides.tunnel.is_none() && several && !flags.is_empty() {
+                bail!(
+                    "{} would replace the settings of tunnel {}, select it with --tunnel",
+                    flags.join(", "),
+                    t.name
+                );
+            }
+            let own = |overridden: bool| !replace || !overridden;
+            configs.push(Config {
+                gateway: match t.gateway {
+                    Some(g) if own(overrides.gateway.is_some()) => g,
+                    _ => base.gateway.clone(),
+                },
+                backend: match t.backend {
+                    Some(b) if own(overrides.backend.is_some()) => b,
+                    _ => base.backend,
+                },
+                netns: match t.netns {
+                    Some(n) if own(overrides.netns.is_some()) => Some(n),
+                    _ => base.netns.clone(),
+                },
+                outputs: if !t.outputs.is_empty() && own(!overrides.outputs.is_empty()) {
+                    t.outputs
+                } else {
+                    base.outputs.clone()
+                },
+                history: match t.history {
+                    Some(h) if own(overrides.history.is_some()) => Some(h),
+                    _ => base.history.clone(),
+                },
+                state_file: match t.state_file {
+                    Some(f) if own(overrides.state_file.is_some()) => Some(f),
+                    _ => base.state_file.clone(),
+                },
+                mappings: if !t.mappings.is_empty() && own(!overrides.mappings.is_empty()) {
+                    t.mappings
+                } else {
+                    base.mappings.clone()
+                },
+                tunnel: Some(t.name),
+                ..base.clone()
+            });
+        }
+        // Only the tunnels which run together must not share files.
+        if let Some(tunnel) = overrides.tunnel {
+            let names: Vec<_> = configs.iter().filter_map(|c| c.tunnel.clone()).collect();
+            configs.retain(|c| c.tunnel.as_ref() == Some(&tunnel));
+            if configs.is_empty() {
+                bail!("Unknown tunnel {}, expected one of {}", tunnel, names.join(", "));
+            }
+        }
+        check_tunnels(&configs)?;
+        Ok(configs)
+    }
+}
+
+// Function to check that tunnels do not write the same files, which they would overwrite.
+fn check_tunnels(configs: &[Config]) -> Result<()> {
+    for (i, a) in configs.iter().enumerate() {
+        let paths = a.outputs.iter().map(|o| &o.path).chain(&a.history).chain(&a.state_file);
+        for path in paths {
+            let shared = configs[i + 1..].iter().find(|b| {
+                b.outputs.iter().any(|o| &o.path == path)
+                    || b.history.as_ref() == Some(path)
+                    || b.state_file.as_ref() == Some(path)
+            });
+            if let Some(b) = shared {
+                bail!(
+                    "Tunnels {} and {} both write {:?}, give each its own output, history and state file",
+                    a.tunnel.as_deref().unwrap_or_default(),
+                    b.tunnel.as_deref().unwrap_or_default(),
+                    path
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// Validated contents of the configuration file.
+#[derive(Debug, Default)]
+struct Validated {
+    gateway: Option<GatewaySpec>,
+    backend: Option<BackendKind>,
+    netns: Option<NetnsSpec>,
+    outputs: Vec<OutputSpec>,
+    history: Option<PathBuf>,
+    state_file: Option<PathBuf>,
+    mappings: Vec<MappingSpec>,
+    tunnels: Vec<ValidatedTunnel>,
+    public_ip_interval: Option<Duration>,
+    renew_at: Option<RenewAt>,
+    renew_jitter: Option<f64>,
+    hooks: HookOverrides,
+    log_level: Option<LevelFilter>,
+}
+
+#[derive(Debug)]
+struct ValidatedTunnel {
+    name: String,
+    gateway: Option<GatewaySpec>,
+    backend: Option<BackendKind>,
+    netns: Option<NetnsSpec>,
+    outputs: Vec<OutputSpec>,
+    history: Option<PathBuf>,
+    state_file: Option<PathBuf>,
+    mappings: Vec<MappingSpec>,
+}
+
+// Function to read and validate a configuration file, reporting errors with their line.
+fn read_file(path: &Path) -> Result<Validated> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read configuration file {:?}", path))?;
+    parse(&content).with_context(|| format!("Invalid configuration file {:?}", path))
+}
+
+fn parse(content: &str) -> Result<Validated> {
+    let file: FileConfig = toml::from_str(content)?;
+    let line = |start: usize| content[..start].matches('\n').count() + 1;
+
+    let outputs = parse_outputs(file.output, line)?;
+    let mappings = parse_mappings(file.mappings, line)?;
+
+    let mut tunnels: Vec<ValidatedTunnel> = Vec::with_capacity(file.tunnels.len());
+    for tunnel in file.tunnels {
+        let name = tunnel.name.get_ref();
+        let at = line(tunnel.name.span().start);
+        if name.is_empty() || name.contains(|c: char| c == ',' || c == '/' || c.is_whitespace()) {
+            bail!("line {}: invalid tunnel name {:?}", at, name);
+        }
+        if tunnels.iter().any(|t| &t.name == name) {
+            bail!("line {}: tunnel {} is declared more than once", at, name);
+        }
+        tunnels.push(ValidatedTunnel {
+            name: name.clone(),
+            gateway: tunnel.gateway,
+            backend: tunnel.backend,
+            netns: tunnel.netns,
+            outputs: parse_outputs(tunnel.output, line)?,
+            history: tunnel.history,
+            state_file: tunnel.state_file,
+            mappings: parse_mappings(tunnel.mappings, line)?,
+        });
+    }
+
+    let public_ip_interval = match file.public_ip_interval {
+        Some(interval) if *interval.get_ref() == 0 => {
+            bail!("line {}: public_ip_interval must be positive", line(interval.span().start));
+        }
+        Some(interval) => Some(Duration::from_secs(interval.into_inner())),
+        None => None,
+    };
+
+    let renew_jitter = match file.renew_jitter {
+        Some(jitter) if !(0.0..1.0).contains(jitter.get_ref()) => {
+            let at = line(jitter.span().start);
+            bail!("line {}: renew_jitter must be a fraction from 0 to 1", at);
+        }
+        Some(jitter) => Some(jitter.into_inner()),
+        None => None,
+    };
+
+    let timeout = match file.hooks.timeout {
+        Some(timeout) if *timeout.get_ref() == 0 => {
+            bail!("line {}: hook timeout must be positive", line(timeout.span().start));
+        }
+        Some(timeout) => Some(Duration::from_secs(timeout.into_inner())),
+        None => None,
+    };
+    let hooks = HookOverrides {
+        created: file.hooks.created,
+        port_changed: file.hooks.port_changed,
+        public_ip_changed: file.hooks.public_ip_changed,
+        lost: file.hooks.lost,
+        timeout,
+    };
+
+    let log_level = match file.log.level {
+        Some(level) => {
+            let at = line(level.span().start);
+            let level = level.into_inner();
+            Some(level.parse().map_err(|_| anyhow!("line {}: invalid log level {:?}", at, level))?)
+        }
+        None => None,
+    };
+
+    Ok(Validated {
+        gateway: file.gateway,
+        backend: file.backend,
+        netns: file.netns,
+        outputs,
+        history: file.history,
+        state_file: file.state_file,
+        mappings,
+        tunnels,
+        public_ip_interval,
+        renew_at: file.renew_at,
+        renew_jitter,
+        hooks,
+        log_level,
+    })
+}
+
+// Function to validate the `output` setting, a single output or an array of them.
+fn parse_outputs(
+    output: Option<Spanned<toml::Value>>,
+    line: impl Fn(usize) -> usize,
+) -> Result<Vec<OutputSpec>> {
+    let mut outputs = vec![];
+    if let Some(output) = output {
+        let at = line(output.span().start);
+        let values = match output.into_inner() {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let Some(value) = value.as_str() else {
+                bail!("line {}: output must be a string or an array of strings", at);
+            };
+            let spec = value.parse().map_err(|e| anyhow!("line {}: {}", at, e))?;
+            outputs.push(spec);
+        }
+    }
+    Ok(outputs)
+}
+
+// Function to validate the `[[mapping]]` tables of the file or of a tunnel.
+fn parse_mappings(
+    file_mappings: Vec<FileMapping>,
+    line: impl Fn(usize) -> usize,
+) -> Result<Vec<MappingSpec>> {
+    let mut mappings: Vec<MappingSpec> = Vec::with_capacity(file_mappings.len());
+    for mapping in file_mappings {
+        let name = mapping.name.get_ref();
+        let at = line(mapping.name.span().start);
+        if name.is_empty() || name.contains(',') {
+            bail!("line {}: invalid mapping name {:?}", at, name);
+        }
+        if mappings.iter().any(|m| &m.name == name) {
+            bail!("line {}: mapping {} is declared more than once", at, name);
+        }
+        let lifetime = match mapping.lifetime {
+            Some(lifetime) if *lifetime.get_ref() == 0 => {
+                let at = line(lifetime.span().start);
+                bail!("line {}: lifetime of mapping {} must be positive", at, name);
+            }
+            Some(lifetime) => lifetime.into_inner(),
+            None => DEFAULT_LIFETIME,
+        };
+        let public_port = match mapping.public_port {
+            Some(port) if *port.get_ref() == 0 => {
+                let at = line(port.span().start);
+                bail!("line {}: public port of mapping {} must be positive", at, name);
+            }
+            Some(port) => port.into_inner(),
+            None => 0,
+        };
+        let public_ports = match mapping.public_ports {
+            Some(range) => {
+                let at = line(range.span().start);
+                Some(parse_port_range(range.get_ref()).map_err(|e| anyhow!("line {}: {}", at, e))?)
+            }
+            None => None,
+        };
+        let spec = MappingSpec {
+            name: name.clone(),
+            protocols: mapping.protocol.unwrap_or(Protocols::Both),
+            internal_port: mapping.internal_port,
+            lifetime,
+            public_port,
+            public_ports,
+            policy: mapping.port_policy.unwrap_or_default(),
+        };
+        spec.check_public_ports().map_err(|e| anyhow!("line {}: {}", at, e))?;
+        mappings.push(spec);
+    }
+    Ok(mappings)
+}
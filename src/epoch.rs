@@ -0,0 +1,29 @@
+use tokio::time::Instant;
+
+// Tracks the gateway's "seconds since start of epoch" to detect when it lost its
+// mappings, following the conservative check of RFC 6886 3.6.
+#[derive(Debug, Default)]
+pub struct EpochTracker {
+    last: Option<(u32, Instant)>,
+}
+
+impl EpochTracker {
+    pub fn new() -> EpochTracker {
+        Default::default()
+    }
+
+    // Record the epoch of a response, returning whether the gateway was reset since the previous one.
+    pub fn observe(&mut self, epoch: u32) -> bool {
+        let now = Instant::now();
+        let reset = match self.last {
+            Some((last, at)) => {
+                // The gateway clock may run up to 1/8 slower than ours, with 2s of slack.
+                let elapsed = now.duration_since(at).as_secs();
+                u64::from(epoch) + 2 < u64::from(last) + elapsed * 7 / 8
+            }
+            None => false,
+        };
+        self.last = Some((epoch, now));
+        reset
+    }
+}
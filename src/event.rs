@@ -0,0 +1,56 @@
+use crate::mapping::Mapping;
+use std::fmt;
+use std::net::Ipv4Addr;
+
+// Something that happened to the mappings maintained by a `PortForwarder`.
+#[derive(Debug, Clone)]
+pub enum Event {
+    // A mapping was created at startup.
+    Created {
+        mapping: Mapping,
+        public_ip: Ipv4Addr,
+    },
+    // The public port of a mapping changed.
+    PortChanged {
+        old: Mapping,
+        new: Mapping,
+        public_ip: Ipv4Addr,
+    },
+    // The public IP address of the gateway changed.
+    PublicIpChanged { old: Ipv4Addr, new: Ipv4Addr },
+    // A mapping could not be renewed on its current port.
+    Lost {
+        mapping: Mapping,
+        public_ip: Ipv4Addr,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    Created,
+    PortChanged,
+    PublicIpChanged,
+    Lost,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Created { .. } => EventKind::Created,
+            Event::PortChanged { .. } => EventKind::PortChanged,
+            Event::PublicIpChanged { .. } => EventKind::PublicIpChanged,
+            Event::Lost { .. } => EventKind::Lost,
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventKind::Created => write!(f, "created"),
+            EventKind::PortChanged => write!(f, "port_changed"),
+            EventKind::PublicIpChanged => write!(f, "public_ip_changed"),
+            EventKind::Lost => write!(f, "lost"),
+        }
+    }
+}
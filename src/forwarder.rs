@@ -0,0 +1,689 @@
+use crate::announce::{self, Announcement};
+use crate::backend::{BackendKind, GatewayError, PublicAddress, Session};
+use crate::backoff::{backoff, long_backoff};
+use crate::client::{
+    self, query_available_mapping, query_gateway, release_mapping, renew_mapping,
+    restore_mapping, Client, PortRefused,
+};
+use crate::codec::Protocol;
+use crate::config::Config;
+use crate::epoch::EpochTracker;
+use crate::event::Event;
+use crate::gateway::GatewaySpec;
+use crate::hooks::Hooks;
+use crate::mapping::{check_specs, Mapping, MappingSpec};
+use crate::netns::NetnsSpec;
+use crate::output::Output;
+use crate::renewal::Renewal;
+use crate::statefile::StateFile;
+use anyhow::{anyhow, Error, Result};
+use log::{debug, error, info, warn};
+use std::future::Future;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+// Number of events kept for subscribers which fall behind.
+const EVENT_CAPACITY: usize = 64;
+
+// Time given to the gateway to grant the saved ports of a mapping again on startup.
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(10);
+
+tokio::task_local! {
+    // Tunnel of the forwarder which runs the current task.
+    static TUNNEL: Option<String>;
+}
+
+// Function to run a future of the forwarder of the given tunnel, so that its logs can tell it apart.
+pub(crate) fn in_tunnel<F: Future>(tunnel: Option<String>, f: F) -> impl Future<Output = F::Output> {
+    TUNNEL.scope(tunnel, f)
+}
+
+/// Name of the tunnel whose forwarder runs the current task, if it has one. Log formatters
+/// can use it to tell apart the logs of several forwarders.
+pub fn current_tunnel() -> Option<String> {
+    TUNNEL.try_with(|t| t.clone()).ok().flatten()
+}
+
+/// Current public address and mappings of a [`PortForwarder`].
+#[derive(Debug, Clone)]
+pub struct State {
+    pub public_ip: Ipv4Addr,
+    pub mappings: Vec<Mapping>,
+}
+
+impl State {
+    /// Whether some mappings are lost, the forwarder retrying to recreate them.
+    pub fn is_degraded(&self) -> bool {
+        self.mappings.iter().any(Mapping::is_lost)
+    }
+}
+
+/// Keeps the mappings of a [`Config`] alive in the background, writing their ports to the
+/// configured outputs and running the hooks on every event.
+pub struct PortForwarder {
+    state: watch::Receiver<State>,
+    events: broadcast::Sender<Event>,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl PortForwarder {
+    /// Create the mappings on the gateway, then spawn a task on the current tokio runtime
+    /// to renew them. While the gateway is unreachable or has no network connection, the
+    /// mappings start lost and are retried in the background; only a gateway refusing port
+    /// forwarding, or a public port refused by the policy of a mapping, fails the start.
+    ///
+    /// The `Created` events are sent before this returns, so subscribers only see the
+    /// later ones; the initial mappings are available through [`PortForwarder::state`].
+    pub async fn start(config: Config) -> Result<PortForwarder> {
+        in_tunnel(config.tunnel.clone(), PortForwarder::start_in_tunnel(config)).await
+    }
+
+    async fn start_in_tunnel(config: Config) -> Result<PortForwarder> {
+        check_specs(&config.mappings)?;
+        // A gateway which cannot be reached yet, as while the VPN starts, only degrades the
+        // forwarder, which connects again before retrying the mappings.
+        let session = Session::default();
+        let connected =
+            client::connect(&config.gateway, config.netns.as_ref(), config.backend, &session);
+        let (n, announcements) = match connected.await {
+            Ok(n) => {
+                let announcements = listen(n.gateway(), config.netns.as_ref());
+                (n, announcements)
+            }
+            Err(e) => {
+                warn!("{:#}", e);
+                (client::unconnected(&config.gateway), None)
+            }
+        };
+        let output = Output::create(&config.outputs, config.history.as_deref())?;
+        let state_file = config.state_file.clone().map(|path| {
+            let mut state_file = StateFile::load(path);
+            let names: Vec<_> = config.mappings.iter().map(|s| s.name.as_str()).collect();
+            state_file.retain(&names);
+            state_file.restore_nonces(n.gateway(), &session);
+            state_file
+        });
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let (state, state_rx) = watch::channel(State {
+            public_ip: Ipv4Addr::UNSPECIFIED,
+            mappings: vec![],
+        });
+        let mut worker = Worker {
+            n,
+            announcements,
+            output,
+            state_file,
+            hooks: Hooks::start(config.hooks.clone(), config.tunnel.clone()),
+            events: events.clone(),
+            state,
+            gateway: config.gateway.clone(),
+            netns: config.netns.clone(),
+            backend: config.backend,
+            session,
+            epoch: EpochTracker::new(),
+            public_ip: Ipv4Addr::UNSPECIFIED,
+            public_ip_interval: config.public_ip_interval,
+            public_ip_at: Instant::now() + config.public_ip_interval,
+            renewal: config.renewal,
+            mappings: Vec::with_capacity(config.mappings.len()),
+        };
+        if let Err(e) = worker.setup(&config).await {
+            // Do not leave the mappings created so far behind.
+            worker.close().await;
+            return Err(e);
+        }
+        let (stop, stop_rx) = oneshot::channel();
+        let task = tokio::spawn(in_tunnel(config.tunnel.clone(), worker.run(stop_rx)));
+        Ok(PortForwarder {
+            state: state_rx,
+            events,
+            stop,
+            task,
+        })
+    }
+
+    /// Receive the events which happen from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Current public address and mappings.
+    pub fn state(&self) -> State {
+        self.state.borrow().clone()
+    }
+
+    /// Wait until the forwarder task ends on its own. Most gateway failures only degrade it,
+    /// so this only happens when the gateway refuses port forwarding altogether, or if the
+    /// task panics, which [`PortForwarder::shutdown`] then reports.
+    pub async fn stopped(&self) {
+        let mut state = self.state.clone();
+        while state.changed().await.is_ok() {}
+    }
+
+    /// Delete the mappings on the gateway, remove the output files and wait for the
+    /// pending hooks. Dropping the forwarder does the same in the background.
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.stop.send(());
+        self.task
+            .await
+            .map_err(|e| anyhow!("Port forwarder task failed: {}", e))?
+    }
+}
+
+// The state owned by the renewal task.
+struct Worker {
+    n: Client,
+    // Public address announcements of the gateway, if they can be received.
+    announcements: Option<UdpSocket>,
+    output: Output,
+    // Ports of the mappings, kept for the next run.
+    state_file: Option<StateFile>,
+    hooks: Hooks,
+    events: broadcast::Sender<Event>,
+    state: watch::Sender<State>,
+    // Resolved again on reconnection, the route to the gateway may have changed.
+    gateway: GatewaySpec,
+    netns: Option<NetnsSpec>,
+    // Negotiated again on reconnection too.
+    backend: BackendKind,
+    // Shared by the successive clients, so that they renew the mappings of the previous ones.
+    session: Session,
+    // Track the gateway epoch from every response to detect when it loses its state.
+    epoch: EpochTracker,
+    public_ip: Ipv4Addr,
+    public_ip_interval: Duration,
+    // When to query the public address next.
+    public_ip_at: Instant,
+    renewal: Renewal,
+    mappings: Vec<Mapping>,
+}
+
+impl Worker {
+    // Query the public address and an available port for every declared mapping. Mappings
+    // which fail for a while, as when the gateway is unreachable or has no network
+    // connection, start lost and are retried with a backoff. Only a gateway refusing port
+    // forwarding, or a port refused by the policy of a mapping, fails the startup.
+    async fn setup(&mut self, config: &Config) -> Result<()> {
+        let reachable = match query_gateway(&mut self.n).await {
+            Ok(pa) => {
+                if let Some(ip) = pa.ip {
+                    self.public_ip = ip;
+                    check_public_ip(ip);
+                }
+                self.epoch.observe(pa.epoch);
+                true
+            }
+            Err(e) if is_fatal(&e) => return Err(e.context("Querying Public IP failed")),
+            Err(e) => {
+                warn!("Querying Public IP failed: {:#}, entering degraded state", e);
+                false
+            }
+        };
+
+        for spec in &config.mappings {
+            // Mappings would fail the same way, only after every retry.
+            let created = if reachable {
+                match self.restore(spec).await {
+                    Some(m) => Ok(m),
+                    None => query_available_mapping(&mut self.n, spec.clone()).await,
+                }
+            } else {
+                Err(anyhow!("Gateway is unreachable"))
+            };
+            let m = match created {
+                Ok(m) => m,
+                Err(e) if is_fatal(&e) || e.is::<PortRefused>() => {
+                    let context = format!("Querying a port mapping for {} failed", spec.name);
+                    return Err(e.context(context));
+                }
+                Err(e) => {
+                    self.mappings.push(Mapping::new(spec.clone(), None, None));
+                    self.fail(self.mappings.len() - 1, e);
+                    continue;
+                }
+            };
+            m.epochs().for_each(|e| {
+                self.epoch.observe(e);
+            });
+            // PCP gateways only tell the public address along with the mappings.
+            if let Some(ip) = m.public_ip().filter(|_| self.public_ip.is_unspecified()) {
+                info!("Public IP is {}", ip);
+                self.public_ip = ip;
+                check_public_ip(ip);
+            }
+            self.mappings.push(m);
+            let i = self.mappings.len() - 1;
+            self.schedule(i, None);
+            self.save(i);
+            // Write the initial PID and port information to the files.
+            self.write_outputs(i)?;
+            self.emit(Event::Created {
+                mapping: self.mappings[i].clone(),
+                public_ip: self.public_ip,
+            });
+        }
+        self.publish();
+        Ok(())
+    }
+
+    // Ask the gateway for the ports a mapping had in the previous run, if they were saved
+    // for this gateway. A gateway which does not grant them does not delay the startup long.
+    async fn restore(&mut self, spec: &MappingSpec) -> Option<Mapping> {
+        let gateway = self.n.gateway();
+        let ports = self.state_file.as_ref()?.ports(&spec.name, gateway);
+        if ports.is_empty() {
+            return None;
+        }
+        let moved = |&(_, private, _): &(_, u16, _)| private != spec.internal_port;
+        if spec.internal_port != 0 && ports.iter().any(moved) {
+            info!("Internal port of {} has changed, requesting new ports", spec.name);
+            return None;
+        }
+        let restored = time::timeout(
+            RESTORE_TIMEOUT,
+            restore_mapping(&mut self.n, spec.clone(), &ports),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(anyhow!(
+                "no matching reply within {}s",
+                RESTORE_TIMEOUT.as_secs()
+            ))
+        });
+        match restored {
+            Ok(m) => {
+                info!("Restored the previous ports of {}", spec.name);
+                Some(m)
+            }
+            Err(e) => {
+                info!(
+                    "Could not restore the previous ports of {}: {}, requesting new ones",
+                    spec.name, e
+                );
+                None
+            }
+        }
+    }
+
+    async fn run(mut self, mut stop: oneshot::Receiver<()>) -> Result<()> {
+        let result = self.renew(&mut stop).await;
+        self.close().await;
+        result
+    }
+
+    // Loop to continuously check and update port mappings until asked to stop.
+    async fn renew(&mut self, stop: &mut oneshot::Receiver<()>) -> Result<()> {
+        loop {
+            // Pick the mapping which is due for renewal first.
+            let i = (0..self.mappings.len())
+                .min_by_key(|&i| self.mappings[i].renew_at)
+                .expect("At least one mapping is declared, as checked on start");
+            // Sleep until it is due, or until the forwarder is stopped.
+            tokio::select! {
+                _ = time::sleep_until(self.mappings[i].renew_at) => {}
+                a = announce::receive(self.announcements.as_ref(), self.n.gateway()) => {
+                    self.announced(a);
+                    continue;
+                }
+                _ = time::sleep_until(self.public_ip_at) => {
+                    let query = tokio::select! {
+                        r = query_gateway(&mut self.n) => r,
+                        _ = &mut *stop => return Ok(()),
+                    };
+                    self.refreshed(query)?;
+                    continue;
+                }
+                _ = &mut *stop => return Ok(()),
+            }
+            let lost = self.mappings[i].is_lost();
+            let renewal = if lost {
+                // Retry on a new socket, the previous one may be tied to a route which is gone.
+                let connected =
+                    client::connect(&self.gateway, self.netns.as_ref(), self.backend, &self.session);
+                match connected.await {
+                    Ok(n) => {
+                        self.announcements = listen(n.gateway(), self.netns.as_ref());
+                        if let Some(state_file) = &self.state_file {
+                            state_file.restore_nonces(n.gateway(), &self.session);
+                        }
+                        self.n = n;
+                    }
+                    Err(e) => warn!("{:#}", e),
+                }
+                let spec = self.mappings[i].spec.clone();
+                tokio::select! {
+                    r = self.recreate(spec) => r,
+                    _ = &mut *stop => return Ok(()),
+                }
+            } else {
+                // Attempt to renew the mapping or find a new available port, unless stopped meanwhile.
+                let renewal = tokio::select! {
+                    r = renew_mapping(&mut self.n, &self.mappings[i]) => r,
+                    _ = &mut *stop => return Ok(()),
+                };
+                match renewal {
+                    Ok(m_) => Ok(m_),
+                    Err(e) => {
+                        let m = &self.mappings[i];
+                        warn!("Failed to renew mapping {}: {}", m.spec.name, e);
+                        self.emit(Event::Lost {
+                            mapping: m.clone(),
+                            public_ip: self.public_ip,
+                        });
+                        let spec = m.spec.clone();
+                        tokio::select! {
+                            r = query_available_mapping(&mut self.n, spec) => r,
+                            _ = &mut *stop => return Ok(()),
+                        }
+                    }
+                }
+            };
+            let m_ = match renewal {
+                Ok(m_) => m_,
+                Err(e) if is_fatal(&e) => {
+                    let name = &self.mappings[i].spec.name;
+                    return Err(e.context(format!("Failed to recreate mapping {}", name)));
+                }
+                Err(e) => {
+                    self.fail(i, e);
+                    self.publish();
+                    continue;
+                }
+            };
+            // A gateway whose epoch went backwards has lost every other mapping too.
+            let mut reset = false;
+            for e in m_.epochs() {
+                reset |= self.epoch.observe(e);
+            }
+            // Update the mapping to continue with the new or renewed ports.
+            let old = mem::replace(&mut self.mappings[i], m_);
+            self.schedule(i, Some(old.lifetime()));
+            self.save(i);
+            if let Some(ip) = self.mappings[i].public_ip() {
+                self.set_public_ip(ip);
+            }
+            let m = &self.mappings[i];
+            if lost || old.ports_differ_from(m) {
+                // The address may have changed along with the ports.
+                self.public_ip_at = Instant::now();
+            }
+            if lost {
+                info!("Mapping {} was recreated after {} failures", m.spec.name, old.failures);
+                self.print_loop_info(i);
+                self.emit(Event::Created {
+                    mapping: self.mappings[i].clone(),
+                    public_ip: self.public_ip,
+                });
+                if !self.mappings.iter().any(Mapping::is_lost) {
+                    info!("Every mapping is forwarded again, leaving degraded state");
+                }
+            } else if old.ports_differ_from(m) {
+                // Check if any of the public ports has changed.
+                info!("Port of {} has changed, updating file...", m.spec.name);
+                // Update the files with the new port information.
+                self.print_loop_info(i);
+                self.emit(Event::PortChanged {
+                    old,
+                    new: self.mappings[i].clone(),
+                    public_ip: self.public_ip,
+                });
+            }
+            if reset {
+                warn!("Gateway epoch went backwards, recreating all mappings...");
+                self.revalidate();
+                // The public address may have changed along with the gateway state.
+                self.public_ip_at = Instant::now();
+            }
+            self.publish();
+        }
+    }
+
+    // Request new ports for a lost mapping. If the gateway was not reached yet, its public
+    // address is queried first, so that the mapping is published along with it.
+    async fn recreate(&mut self, spec: MappingSpec) -> Result<Mapping> {
+        if self.public_ip.is_unspecified() {
+            let pa = query_gateway(&mut self.n).await?;
+            self.refreshed(Ok(pa))?;
+        }
+        query_available_mapping(&mut self.n, spec).await
+    }
+
+    // Delete every mapping on the gateway so the ports do not linger until they expire,
+    // remove the output files and let the pending hooks complete.
+    async fn close(self) {
+        let Worker {
+            mut n,
+            output,
+            hooks,
+            mappings,
+            ..
+        } = self;
+        // Lost mappings are not forwarded anymore, and the gateway is likely unreachable.
+        for m in mappings.iter().filter(|m| !m.is_lost()) {
+            if let Err(e) = release_mapping(&mut n, m).await {
+                error!("Failed to release mapping {}: {}", m.spec.name, e);
+            }
+        }
+        // The ports the output files list are no longer forwarded.
+        if let Err(e) = output.remove() {
+            error!("{:#}", e);
+        }
+        hooks.finish().await;
+    }
+
+    // Handle a public address announced by the gateway. Gateways repeat an announcement
+    // several times, so mappings are only renewed when it brings news: a new address, or
+    // an epoch showing that the gateway lost its state. PCP gateways only announce the
+    // epoch, the renewals then telling the address.
+    fn announced(&mut self, a: Announcement) {
+        let reset = self.epoch.observe(a.epoch);
+        if a.public_ip.is_none_or(|ip| ip == self.public_ip) && !reset {
+            debug!("Gateway announced nothing new (epoch {})", a.epoch);
+            return;
+        }
+        match a.public_ip {
+            Some(ip) => info!("Gateway announced public IP {} (epoch {})", ip, a.epoch),
+            None => info!("Gateway announced epoch {}", a.epoch),
+        }
+        if reset {
+            warn!("Gateway epoch went backwards, recreating all mappings...");
+        }
+        if let Some(ip) = a.public_ip {
+            self.set_public_ip(ip);
+        }
+        self.revalidate();
+        self.publish();
+    }
+
+    // Handle the reply to a periodic query of the public address, failing only if the
+    // gateway refuses to serve this client anymore.
+    fn refreshed(&mut self, query: Result<PublicAddress>) -> Result<()> {
+        self.public_ip_at = Instant::now() + self.public_ip_interval;
+        match query {
+            Ok(pa) => {
+                if self.epoch.observe(pa.epoch) {
+                    warn!("Gateway epoch went backwards, recreating all mappings...");
+                    self.revalidate();
+                }
+                if let Some(ip) = pa.ip {
+                    self.set_public_ip(ip);
+                }
+                self.publish();
+            }
+            Err(e) if is_fatal(&e) => return Err(e.context("Failed to query the public IP")),
+            Err(e) => warn!("Failed to query the public IP: {}", e),
+        }
+        Ok(())
+    }
+
+    // Set when to renew mapping `i`, which was just granted. The lifetime it was previously
+    // granted, if any, avoids repeating the same notices on every renewal.
+    fn schedule(&mut self, i: usize, previous: Option<Duration>) {
+        let m = &mut self.mappings[i];
+        let lifetime = m.lifetime();
+        let requested = Duration::from_secs(m.spec.lifetime.into());
+        let (delay, fallback) = self.renewal.delay(lifetime);
+        if previous != Some(lifetime) {
+            if lifetime < requested {
+                info!(
+                    "Gateway granted mapping {} for {}s instead of the {}s requested",
+                    m.spec.name,
+                    lifetime.as_secs(),
+                    requested.as_secs()
+                );
+            }
+            if fallback {
+                warn!(
+                    "Renewal margin {} does not fit in the {}s lifetime of mapping {}, renewing it at half its lifetime",
+                    self.renewal.at,
+                    lifetime.as_secs(),
+                    m.spec.name
+                );
+            }
+        }
+        debug!("Renewing mapping {} in {}s", m.spec.name, delay.as_secs_f64());
+        m.renew_at = Instant::now() + delay;
+    }
+
+    // Save the ports of mapping `i` to the state file, if any.
+    fn save(&mut self, i: usize) {
+        if let Some(state_file) = &mut self.state_file {
+            if let Err(e) = state_file.save(&self.mappings[i], self.n.gateway(), &self.session) {
+                error!("{:#}", e);
+            }
+        }
+    }
+
+    // Renew every mapping right away, as the gateway may have lost or changed them.
+    fn revalidate(&mut self) {
+        let now = Instant::now();
+        self.mappings.iter_mut().for_each(|m| m.renew_at = now);
+    }
+
+    // Record the public address of the gateway, updating the outputs if it changed.
+    fn set_public_ip(&mut self, public_ip: Ipv4Addr) {
+        if public_ip == self.public_ip {
+            return;
+        }
+        // The address is unknown until the gateway is first reached.
+        if self.public_ip.is_unspecified() {
+            info!("Public IP is {}", public_ip);
+        } else {
+            info!("Public IP has changed from {} to {}", self.public_ip, public_ip);
+        }
+        check_public_ip(public_ip);
+        let old = mem::replace(&mut self.public_ip, public_ip);
+        if let Err(e) = self.output.write(&self.mappings, public_ip) {
+            error!("{:#}", e);
+        }
+        if old.is_unspecified() {
+            return;
+        }
+        self.emit(Event::PublicIpChanged {
+            old,
+            new: public_ip,
+        });
+    }
+
+    // Mark mapping `i` as lost after a failed renewal or creation, to be retried after a
+    // backoff, a long one if the gateway has no port left. The forwarder is degraded until it
+    // is recreated, and the output files no longer list its ports.
+    fn fail(&mut self, i: usize, e: Error) {
+        let m = &mut self.mappings[i];
+        m.failures += 1;
+        let delay = match GatewayError::find(&e) {
+            Some(GatewayError::OutOfResources) => long_backoff(),
+            _ => backoff(m.failures),
+        };
+        m.renew_at = Instant::now() + delay;
+        // Mappings which failed at startup were never granted any port.
+        let verb = if m.port().is_some() { "recreate" } else { "create" };
+        warn!(
+            "Failed to {} mapping {}: {:#}, retrying in {}s",
+            verb,
+            m.spec.name,
+            e,
+            delay.as_secs()
+        );
+        if m.failures == 1 {
+            warn!("Mapping {} is lost, entering degraded state", m.spec.name);
+            self.print_loop_info(i);
+        }
+    }
+
+    // Send an event to the hooks and the subscribers.
+    fn emit(&self, event: Event) {
+        self.hooks.fire(&event);
+        // There may be no subscriber.
+        let _ = self.events.send(event);
+    }
+
+    // Make the current mappings visible through `PortForwarder::state`.
+    fn publish(&self) {
+        self.state.send_replace(State {
+            public_ip: self.public_ip,
+            mappings: self.mappings.clone(),
+        });
+    }
+
+    // Write the PID and port information of the mappings, after mapping `i` got new ports.
+    fn print_loop_info(&mut self, i: usize) {
+        if let Err(e) = self.write_outputs(i) {
+            error!("{:#}", e);
+        }
+    }
+
+    fn write_outputs(&mut self, i: usize) -> Result<()> {
+        let m = &self.mappings[i];
+        if m.ports_differ() {
+            warn!(
+                "TCP and UDP public ports of {} differ (TCP: {}, UDP: {})",
+                m.spec.name,
+                m.public_port(Protocol::TCP).unwrap_or_default(),
+                m.public_port(Protocol::UDP).unwrap_or_default()
+            );
+        }
+        self.output.write(&self.mappings, self.public_ip)?;
+        self.output.record(&self.mappings[i], self.public_ip)
+    }
+}
+
+// Function to subscribe to the announcements of the gateway, which are only a shortcut
+// over the renewals, so the forwarder goes on without them.
+fn listen(gateway: Ipv4Addr, netns: Option<&NetnsSpec>) -> Option<UdpSocket> {
+    match announce::listen(gateway, netns) {
+        Ok(socket) => Some(socket),
+        Err(e) => {
+            warn!("{:#}", e);
+            None
+        }
+    }
+}
+
+// Function to warn when the gateway reports a public address which is not, as ports
+// mapped on it are not reachable from the internet through the NAT in front of it.
+fn check_public_ip(ip: Ipv4Addr) {
+    let [a, b, ..] = ip.octets();
+    if a == 100 && b & 0xc0 == 64 {
+        warn!(
+            "Public IP {} is a carrier-grade NAT address (100.64.0.0/10), forwarded ports are likely unreachable",
+            ip
+        );
+    } else if ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() {
+        warn!(
+            "Public IP {} is a private address, the gateway is behind another NAT and forwarded ports are likely unreachable",
+            ip
+        );
+    }
+}
+
+// Function to tell whether a failure comes from a gateway which will never forward ports.
+fn is_fatal(e: &Error) -> bool {
+    GatewayError::find(e).is_some_and(|e| e.is_fatal())
+}
@@ -0,0 +1,217 @@
+use crate::config::DEFAULT_GATEWAY;
+use anyhow::{bail, Context, Error, Result};
+use log::info;
+use std::cmp::Reverse;
+use std::fmt;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+// Routing table of the network namespace of the calling thread, which may differ from the
+// one of the process with --netns.
+pub const ROUTE_PATH: &str = "/proc/thread-self/net/route";
+
+// Route flags, from linux/route.h.
+const RTF_UP: u32 = 0x1;
+const RTF_GATEWAY: u32 = 0x2;
+
+// How to find the NAT-PMP gateway: a fixed address, the gateway of the default route, or
+// the gateway of the routes of an interface.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum GatewaySpec {
+    Address(Ipv4Addr),
+    Auto,
+    Interface(String),
+}
+
+impl Default for GatewaySpec {
+    fn default() -> Self {
+        GatewaySpec::Address(DEFAULT_GATEWAY)
+    }
+}
+
+impl fmt::Display for GatewaySpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GatewaySpec::Address(addr) => write!(f, "{}", addr),
+            GatewaySpec::Auto => write!(f, "auto"),
+            GatewaySpec::Interface(iface) => write!(f, "auto:{}", iface),
+        }
+    }
+}
+
+impl FromStr for GatewaySpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "auto" {
+            return Ok(GatewaySpec::Auto);
+        }
+        if let Some(iface) = s.strip_prefix("auto:") {
+            if iface.is_empty() || iface.contains(|c: char| c == '/' || c.is_whitespace()) {
+                bail!("Invalid interface name {:?} in gateway {:?}", iface, s);
+            }
+            return Ok(GatewaySpec::Interface(iface.to_owned()));
+        }
+        match s.parse() {
+            Ok(addr) => Ok(GatewaySpec::Address(addr)),
+            Err(_) => bail!(
+                "Invalid gateway {:?}, expected an IPv4 address, auto or auto:INTERFACE",
+                s
+            ),
+        }
+    }
+}
+
+// A route which is up, as listed in /proc/net/route.
+#[derive(Debug)]
+struct Route {
+    iface: String,
+    destination: Ipv4Addr,
+    gateway: Ipv4Addr,
+    mask: Ipv4Addr,
+    metric: u32,
+    // Whether the route goes through the gateway, rather than being on-link.
+    through_gateway: bool,
+}
+
+impl Route {
+    fn prefix(&self) -> u32 {
+        u32::from(self.mask).count_ones()
+    }
+}
+
+impl GatewaySpec {
+    // Get the address of the gateway, reading the routing table of the current network
+    // namespace in the auto modes.
+    pub fn resolve(&self) -> Result<Ipv4Addr> {
+        if let GatewaySpec::Address(addr) = self {
+            return Ok(*addr);
+        }
+        let routes = fs::read_to_string(ROUTE_PATH)
+            .with_context(|| format!("Failed to read the routing table {}", ROUTE_PATH))?;
+        self.resolve_from(&routes)
+    }
+
+    // Get the address of the gateway from the content of a /proc/net/route table.
+    pub fn resolve_from(&self, routes: &str) -> Result<Ipv4Addr> {
+        let iface = match self {
+            GatewaySpec::Address(addr) => return Ok(*addr),
+            GatewaySpec::Auto => None,
+            GatewaySpec::Interface(iface) => Some(iface.as_str()),
+        };
+        let (routes, on_iface) = parse_routes(routes, iface)?;
+        // The widest route wins, such as a default route over the 0.0.0.0/1 and
+        // 128.0.0.0/1 pair of OpenVPN, then the one with the lowest metric.
+        let route = routes
+            .iter()
+            .filter(|r| r.through_gateway)
+            .filter(|r| iface.is_some() || r.mask.is_unspecified())
+            .min_by_key(|r| (r.prefix(), r.metric));
+        match (route, iface) {
+            (Some(r), None) => {
+                info!(
+                    "Using gateway {} of the default route on {} (metric {})",
+                    r.gateway, r.iface, r.metric
+                );
+                Ok(r.gateway)
+            }
+            (Some(r), Some(_)) => {
+                let prefix = u32::from(r.mask).count_ones();
+                info!(
+                    "Using gateway {} of the route to {}/{} on {} (metric {})",
+                    r.gateway,
+                    r.destination,
+                    prefix,
+                    r.iface,
+                    r.metric
+                );
+                Ok(r.gateway)
+            }
+            (None, None) => bail!(
+                "No default route through a gateway in {}, set the gateway address or an interface with auto:INTERFACE",
+                ROUTE_PATH
+            ),
+            (None, Some(iface)) if on_iface => match point_to_point(&routes) {
+                Some(candidate) => Ok(candidate),
+                None => bail!(
+                    "No route on {} goes through a gateway or to a subnet, set the gateway address instead",
+                    iface
+                ),
+            },
+            (None, Some(iface)) => bail!(
+                "No route on {} in {}, is the interface up in this network namespace?",
+                iface,
+                ROUTE_PATH
+            ),
+        }
+    }
+}
+
+// Function to guess the gateway of a point-to-point interface, such as a WireGuard one,
+// whose routes are all on-link: the peer of a host route, or else the first address of
+// the narrowest subnet, as 10.2.0.1 for 10.2.0.0/24 on ProtonVPN. Routes wider than /8,
+// such as the default ones of a full tunnel, tell nothing about the gateway.
+fn point_to_point(routes: &[Route]) -> Option<Ipv4Addr> {
+    let r = routes
+        .iter()
+        .filter(|r| !r.through_gateway && (8..=32).contains(&r.prefix()))
+        .max_by_key(|r| (r.prefix(), Reverse(r.metric)))?;
+    let (candidate, why) = match r.prefix() {
+        32 => (r.destination, "its peer"),
+        _ => (Ipv4Addr::from(u32::from(r.destination) + 1), "the first address"),
+    };
+    info!(
+        "Using gateway {}, {} of the route to {}/{} on {} (metric {}), as no route on this point-to-point interface goes through a gateway",
+        candidate,
+        why,
+        r.destination,
+        r.prefix(),
+        r.iface,
+        r.metric
+    );
+    Some(candidate)
+}
+
+// Function to list the routes of the table which are up, on the given interface if any,
+// along with whether that interface has any route at all.
+fn parse_routes(content: &str, iface: Option<&str>) -> Result<(Vec<Route>, bool)> {
+    let mut routes = vec![];
+    let mut on_iface = false;
+    // The first line holds the column names.
+    for (i, line) in content.lines().enumerate().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [name, destination, gateway, flags, _, _, metric, mask, ..] = fields[..] else {
+            continue;
+        };
+        if iface.is_some_and(|iface| iface != name) {
+            continue;
+        }
+        on_iface = true;
+        let parse = || -> Result<Option<Route>> {
+            let flags = u32::from_str_radix(flags, 16)?;
+            if flags & RTF_UP == 0 {
+                return Ok(None);
+            }
+            Ok(Some(Route {
+                iface: name.to_owned(),
+                destination: addr(destination)?,
+                gateway: addr(gateway)?,
+                mask: addr(mask)?,
+                metric: metric.parse()?,
+                through_gateway: flags & RTF_GATEWAY != 0,
+            }))
+        };
+        let route = parse()
+            .with_context(|| format!("Invalid line {} in {}: {:?}", i + 1, ROUTE_PATH, line))?;
+        routes.extend(route);
+    }
+    Ok((routes, on_iface))
+}
+
+// Function to parse an address of the table, printed as the hexadecimal value of its bytes
+// in memory.
+fn addr(hex: &str) -> Result<Ipv4Addr> {
+    let value = u32::from_str_radix(hex, 16)?;
+    Ok(Ipv4Addr::from(value.to_ne_bytes()))
+}
@@ -0,0 +1,161 @@
+use crate::codec::Protocol;
+use crate::event::{Event, EventKind};
+use crate::forwarder::in_tunnel;
+use crate::mapping::Mapping;
+use log::{error, info, warn};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+// Time a hook command may run before it is killed, when not configured.
+pub const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Shell commands to run on mapping events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookCommands {
+    pub created: Option<String>,
+    pub port_changed: Option<String>,
+    pub public_ip_changed: Option<String>,
+    pub lost: Option<String>,
+    pub timeout: Duration,
+}
+
+impl Default for HookCommands {
+    fn default() -> Self {
+        HookCommands {
+            created: None,
+            port_changed: None,
+            public_ip_changed: None,
+            lost: None,
+            timeout: DEFAULT_HOOK_TIMEOUT,
+        }
+    }
+}
+
+impl HookCommands {
+    fn get(&self, kind: EventKind) -> Option<&String> {
+        match kind {
+            EventKind::Created => self.created.as_ref(),
+            EventKind::PortChanged => self.port_changed.as_ref(),
+            EventKind::PublicIpChanged => self.public_ip_changed.as_ref(),
+            EventKind::Lost => self.lost.as_ref(),
+        }
+    }
+}
+
+// Function to build the `NATPMP_*` environment variables passed to hook commands.
+fn vars(event: &Event) -> Vec<(&'static str, String)> {
+    let mut vars = vec![];
+    let (old, new, public_ip) = match event {
+        Event::Created { mapping, public_ip } => (None, Some(mapping), public_ip),
+        Event::PortChanged {
+            old,
+            new,
+            public_ip,
+        } => (Some(old), Some(new), public_ip),
+        Event::Lost { mapping, public_ip } => (Some(mapping), None, public_ip),
+        Event::PublicIpChanged { old, new } => {
+            vars.push(("NATPMP_PUBLIC_IP", new.to_string()));
+            vars.push(("NATPMP_OLD_PUBLIC_IP", old.to_string()));
+            return vars;
+        }
+    };
+    vars.push(("NATPMP_PUBLIC_IP", public_ip.to_string()));
+    if let Some(m) = new.or(old) {
+        vars.push(("NATPMP_MAPPING", m.spec.name.clone()));
+        vars.push(("NATPMP_PROTOCOL", m.spec.protocols.to_string()));
+    }
+    if let Some(port) = new.and_then(Mapping::port) {
+        vars.push(("NATPMP_PORT", port.to_string()));
+    }
+    if let Some(port) = old.and_then(Mapping::port) {
+        vars.push(("NATPMP_OLD_PORT", port.to_string()));
+    }
+    for (protocol, key, old_key) in [
+        (Protocol::TCP, "NATPMP_TCP_PORT", "NATPMP_OLD_TCP_PORT"),
+        (Protocol::UDP, "NATPMP_UDP_PORT", "NATPMP_OLD_UDP_PORT"),
+    ] {
+        if let Some(port) = new.and_then(|m| m.public_port(protocol)) {
+            vars.push((key, port.to_string()));
+        }
+        if let Some(port) = old.and_then(|m| m.public_port(protocol)) {
+            vars.push((old_key, port.to_string()));
+        }
+    }
+    vars
+}
+
+// Runs hook commands one at a time in the background, so that they keep their order
+// without delaying the renewals.
+pub struct Hooks {
+    tx: Option<UnboundedSender<Event>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Hooks {
+    // Start running the hooks of the forwarder of a tunnel, if it is named.
+    pub fn start(commands: HookCommands, tunnel: Option<String>) -> Hooks {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Event>();
+        let worker = tokio::spawn(in_tunnel(tunnel.clone(), async move {
+            while let Some(event) = rx.recv().await {
+                if let Some(command) = commands.get(event.kind()) {
+                    run_hook(command, &event, tunnel.as_deref(), commands.timeout).await;
+                }
+            }
+        }));
+        Hooks {
+            tx: Some(tx),
+            worker: Some(worker),
+        }
+    }
+
+    pub fn fire(&self, event: &Event) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(event.clone());
+        }
+    }
+
+    // Wait for the pending hooks to complete.
+    pub async fn finish(mut self) {
+        self.tx = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.await;
+        }
+    }
+}
+
+// Function to run a hook command through the shell, logging its output and exit status.
+async fn run_hook(command: &str, event: &Event, tunnel: Option<&str>, timeout: Duration) {
+    let kind = event.kind();
+    info!("Running {} hook: {}", kind, command);
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env("NATPMP_EVENT", kind.to_string())
+        .envs(vars(event))
+        .envs(tunnel.map(|t| ("NATPMP_TUNNEL", t)))
+        .kill_on_drop(true);
+    match time::timeout(timeout, cmd.output()).await {
+        Err(_) => error!(
+            "{} hook timed out after {}s and was killed",
+            kind,
+            timeout.as_secs()
+        ),
+        Ok(Err(e)) => error!("Failed to run {} hook: {}", kind, e),
+        Ok(Ok(output)) => {
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                info!("{} hook stdout: {}", kind, line);
+            }
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                warn!("{} hook stderr: {}", kind, line);
+            }
+            if output.status.success() {
+                info!("{} hook exited with {}", kind, output.status);
+            } else {
+                warn!("{} hook exited with {}", kind, output.status);
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! NAT-PMP port forwarding for ProtonVPN and other NAT-PMP gateways.
+//!
+//! A [`PortForwarder`] keeps the mappings of a [`Config`] alive in the background:
+//!
+//! ```no_run
+//! use natpmp_setup::{Config, PortForwarder};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let forwarder = PortForwarder::start(Config::default()).await?;
+//! let mut events = forwarder.subscribe();
+//! for m in forwarder.state().mappings {
+//!     println!("{}: {:?}", m.spec.name, m.port());
+//! }
+//! while let Ok(event) = events.recv().await {
+//!     println!("{:?}", event);
+//! }
+//! forwarder.shutdown().await
+//! # }
+//! ```
+
+mod announce;
+pub mod backend;
+mod backoff;
+pub mod client;
+pub mod codec;
+pub mod config;
+mod epoch;
+pub mod event;
+mod forwarder;
+pub mod gateway;
+pub mod hooks;
+pub mod mapping;
+pub mod netns;
+pub mod output;
+mod pcp;
+pub mod pmp;
+pub mod renewal;
+pub mod statefile;
+mod upnp;
+
+pub use config::Config;
+pub use event::{Event, EventKind};
+pub use forwarder::{current_tunnel, PortForwarder, State};
@@ -1,168 +1,363 @@
-use anyhow::{anyhow, bail, Result};
-use natpmp::*;
-use std::env;
-use std::fs::File;
-use std::io::{Write, Result as IoResult};
-use std::process;
-use std::thread;
-use std::time::Duration;
-
-fn main() -> Result<()> {
-    // Retrieve the gateway IP from environment variable or use a default.
-    let gateway = env::var("NATPMP_GATEWAY_IP").unwrap_or("10.2.0.1".to_owned());
-    // Create a new NAT-PMP client using the gateway IP.
+mod cli;
+
+use anyhow::{bail, Error, Result};
+use clap::Parser;
+use cli::{Cli, Command, ReleaseArgs};
+use log::{error, info};
+use natpmp_setup::backend::Session;
+use natpmp_setup::client::{self, query_available_mapping, query_gateway, release_port, Client};
+use natpmp_setup::codec::Protocol;
+use natpmp_setup::mapping::check_specs;
+use natpmp_setup::output::{self, Format, OutputSpec};
+use natpmp_setup::statefile::StateFile;
+use natpmp_setup::{current_tunnel, Config, PortForwarder};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::future::{self, Future};
+use std::io::Write;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::task::Poll;
+use tokio::signal::unix::{signal, SignalKind};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let configs = Config::load(cli.config.as_deref(), cli.overrides())?;
+    env_logger::Builder::new()
+        .filter_level(configs[0].log_level)
+        .format(|buf, record| {
+            // Tell apart the logs of the forwarders of several tunnels.
+            let tunnel = current_tunnel().map(|t| format!("{}: ", t)).unwrap_or_default();
+            writeln!(
+                buf,
+                "[{} {:<5}] {}{}",
+                buf.timestamp(),
+                record.level(),
+                tunnel,
+                record.args()
+            )
+        })
+        .init();
+
+    // These only read local files and do not talk to the gateway.
+    match &cli.command {
+        Command::Status(_) => return status(&configs),
+        Command::CheckConfig => return check_config(&cli, &configs),
+        _ => {}
+    }
+
+    if let Command::Run(_) = cli.command {
+        return run(configs).await;
+    }
+
+    let config = single(configs)?;
+    // Create a new client using the gateway IP.
+    let session = Session::default();
     let mut n =
-        Natpmp::new_with((&gateway).parse().unwrap()).expect("Parsing gateway address failed!");
+        client::connect(&config.gateway, config.netns.as_ref(), config.backend, &session).await?;
+    // PCP mappings of a daemon are only deleted with the nonces it saved.
+    if let Some(path) = &config.state_file {
+        StateFile::load(path.clone()).restore_nonces(n.gateway(), &session);
+    }
+    match cli.command {
+        Command::Once(_) => once(&mut n, &config).await,
+        Command::Release(args) => release(&mut n, args).await,
+        Command::PublicIp => {
+            let Some(ip) = query_gateway(&mut n).await?.ip else {
+                bail!(
+                    "The gateway speaks PCP, which only tells the public IP along with mappings, use once instead"
+                );
+            };
+            println!("{}", ip);
+            Ok(())
+        }
+        Command::Run(_) | Command::Status(_) | Command::CheckConfig => unreachable!(),
+    }
+}
 
-    // Retrieve the first command line argument as the filename for the output file.
-    let filename = env::args().nth(1).expect("No file name provided as argument");
+// Function to get the output files, at least one being required by the daemon and status.
+fn outputs(config: &Config) -> Result<&[OutputSpec]> {
+    if config.outputs.is_empty() {
+        bail!("No output file given, use --output, NATPMP_OUTPUT or the configuration file");
+    }
+    Ok(&config.outputs)
+}
 
-    // Open or create the file where PID and port information will be written.
-    let mut file = File::create(filename)?;
+// Function to get the only configuration, the commands talking to a single gateway
+// needing a tunnel to be selected if the file declares several.
+fn single(mut configs: Vec<Config>) -> Result<Config> {
+    if configs.len() > 1 {
+        let names: Vec<_> = configs.iter().filter_map(|c| c.tunnel.clone()).collect();
+        bail!("Several tunnels are declared ({}), select one with --tunnel", names.join(", "));
+    }
+    Ok(configs.remove(0))
+}
 
-    // Query the gateway for public IP address, handle failures.
-    let _ = query_gateway(&mut n).expect("Querying Public IP failed!");
+// Function to run the daemon, keeping the mappings of every tunnel alive until a
+// termination signal, or until no tunnel is left.
+async fn run(configs: Vec<Config>) -> Result<()> {
+    for config in &configs {
+        outputs(config).map_err(|e| tunnel_error(config, e))?;
+    }
 
-    // Query for an available port using NAT-PMP.
-    let mut mr = query_available_port(&mut n).expect("Querying a Port Mapping failed!");
-    // Write the initial PID and port information to the file.
-    print_loop_info(&mut file, mr.public_port()).expect("Failed to write loop information.");
+    // Listen for termination signals while the mappings are kept alive, a second one
+    // kills the process.
+    let term = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        flag::register_conditional_shutdown(signal, 1, term.clone())?;
+        flag::register(signal, term.clone())?;
+    }
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
 
-    // Infinite loop to continuously check and update port mappings.
+    // A tunnel which fails, to start or later, does not stop the others. They start
+    // together, so that a gateway which does not answer does not hold the others back.
+    let several = configs.len() > 1;
+    let mut starting: Vec<_> = configs
+        .into_iter()
+        .map(|config| {
+            Box::pin(async move {
+                let started = PortForwarder::start(config.clone()).await;
+                (config, started)
+            })
+        })
+        .collect();
+    let mut forwarders = Vec::with_capacity(starting.len());
+    while !starting.is_empty() {
+        // The tunnels which are still starting are dropped on a signal, their mappings
+        // expiring on their own.
+        let started = tokio::select! {
+            _ = sigterm.recv() => {
+                info!("Received SIGTERM, releasing mappings...");
+                None
+            }
+            _ = sigint.recv() => {
+                info!("Received SIGINT, releasing mappings...");
+                None
+            }
+            started = first(&mut starting) => Some(started),
+        };
+        match started {
+            Some((config, Ok(forwarder))) => forwarders.push((config, forwarder)),
+            Some((config, Err(e))) if several => {
+                error!("{:#}, skipping it", tunnel_error(&config, e))
+            }
+            Some((config, Err(e))) => return Err(tunnel_error(&config, e)),
+            None => return shutdown(forwarders.into_iter().map(|(_, f)| f).collect()).await,
+        }
+    }
+    if forwarders.is_empty() {
+        bail!("No tunnel could be started");
+    }
+    // Stop on a signal, or once every forwarder has failed, returning the error of the last.
     loop {
-        // Sleep for half the lifetime of the port mapping before renewing.
-        thread::sleep(mr.lifetime().clone() / 2);
-        // Attempt to renew the port mapping or find a new available port.
-        let mr_ = query_port(&mut n, mr.private_port(), mr.public_port(), true)
-            .or(query_available_port(&mut n))
-            .expect("Every renewal method failed!");
-        // Check if the public port has changed.
-        if mr.public_port() != mr_.public_port() {
-            println!("Port has changed, updating file...");
-            // Update the file with the new port information.
-            print_loop_info(&mut file, mr_.public_port())
-                .expect("Failed to write loop information.");
+        let stopped = {
+            let mut stopped: Vec<_> =
+                forwarders.iter().map(|(_, f)| Box::pin(f.stopped())).collect();
+            let any_stopped = future::poll_fn(|cx| {
+                let i = stopped.iter_mut().position(|f| f.as_mut().poll(cx).is_ready());
+                i.map_or(Poll::Pending, Poll::Ready)
+            });
+            tokio::select! {
+                _ = sigterm.recv() => {
+                    info!("Received SIGTERM, releasing mappings...");
+                    None
+                }
+                _ = sigint.recv() => {
+                    info!("Received SIGINT, releasing mappings...");
+                    None
+                }
+                i = any_stopped => Some(i),
+            }
+        };
+        let Some(i) = stopped else {
+            break;
+        };
+        let (config, forwarder) = forwarders.remove(i);
+        let result = forwarder.shutdown().await.map_err(|e| tunnel_error(&config, e));
+        if forwarders.is_empty() {
+            return result;
+        }
+        if let Err(e) = result {
+            error!("{:#}, the other tunnels keep running", e);
         }
-        // Update the mapping response to continue with the new or renewed mapping.
-        mr = mr_;
     }
+    shutdown(forwarders.into_iter().map(|(_, f)| f).collect()).await
 }
 
-// Function to write the PID and port information to a file.
-fn print_loop_info(file: &mut File, port: u16) -> IoResult<()> {
-    let pid = process::id();  // Get the current process ID.
-    writeln!(file, "{},{}", pid, port)?;  // Write the PID and port to the file.
-    Ok(())
+// Function to wait for the first of several futures to complete, removing it.
+async fn first<F: Future + Unpin>(futures: &mut Vec<F>) -> F::Output {
+    future::poll_fn(|cx| {
+        let ready = futures.iter_mut().enumerate().find_map(|(i, f)| match Pin::new(f).poll(cx) {
+            Poll::Ready(output) => Some((i, output)),
+            Poll::Pending => None,
+        });
+        let Some((i, output)) = ready else {
+            return Poll::Pending;
+        };
+        futures.remove(i);
+        Poll::Ready(output)
+    })
+    .await
+}
+
+// Function to stop every forwarder, returning the first error.
+async fn shutdown(forwarders: Vec<PortForwarder>) -> Result<()> {
+    let mut result = Ok(());
+    for forwarder in forwarders {
+        let r = forwarder.shutdown().await;
+        if result.is_ok() {
+            result = r;
+        }
+    }
+    result
 }
 
-// Function to query the gateway for a public IP address.
-fn query_gateway(n: &mut Natpmp) -> Result<GatewayResponse> {
-    let mut timeout = 250;
-    while timeout <= 64000 {
-        // Send a public address request to the gateway.
-        n.send_public_address_request()
-            .map_err(|err| anyhow!("Fail with {:?}", err))?;
+// Function to tell which tunnel an error comes from, if the configuration names it.
+fn tunnel_error(config: &Config, e: Error) -> Error {
+    match &config.tunnel {
+        Some(tunnel) => e.context(format!("Tunnel {}", tunnel)),
+        None => e,
+    }
+}
+
+// Function to create the mappings once and print their ports, leaving them to expire.
+async fn once(n: &mut Client, config: &Config) -> Result<()> {
+    check_specs(&config.mappings)?;
+    for spec in &config.mappings {
+        let m = query_available_mapping(n, spec.clone()).await?;
+        let port = |p: Option<u16>| p.map(|p| p.to_string()).unwrap_or("-".to_owned());
+        // PCP gateways tell the public IP along with the mapping.
+        let public_ip = m.public_ip().map(|ip| format!(", public IP {}", ip)).unwrap_or_default();
         println!(
-            "Public address request sent! (will timeout in {}ms)",
-            timeout
+            "{}: TCP {}, UDP {}, lifetime {}s{}",
+            m.spec.name,
+            port(m.public_port(Protocol::TCP)),
+            port(m.public_port(Protocol::UDP)),
+            m.lifetime().as_secs(),
+            public_ip
         );
-        // Wait for a response or timeout.
-        thread::sleep(Duration::from_millis(timeout));
-        match n.read_response_or_retry() {
-            Err(e) => match e {
-                Error::NATPMP_TRYAGAIN => println!("Try again later"),
-                _ => return Err(anyhow!("Try again: {:?}", e)),
-            },
-            Ok(Response::Gateway(gr)) => {
-                // Successfully received a response with the public IP.
-                println!(
-                    "Got response: IP: {}, Epoch: {}",
-                    gr.public_address(),
-                    gr.epoch()
-                );
-                return Ok(gr);
-            }
-            _ => {
-                bail!("Expecting a gateway response");
-            }
-        };
-        // Increase timeout for the next attempt.
-        timeout *= 2;
     }
-    bail!("Querying gateway failed!");
+    Ok(())
+}
+
+// Function to delete the mapping of an internal port, UPnP gateways being asked for its
+// public port.
+async fn release(n: &mut Client, args: ReleaseArgs) -> Result<()> {
+    for protocol in args.protocol.iter() {
+        release_port(n, protocol, args.internal_port, 0).await?;
+        println!("Released {:?} internal port {}", protocol, args.internal_port);
+    }
+    Ok(())
 }
 
-// Function to query an available port using NAT-PMP.
-fn query_available_port(n: &mut Natpmp) -> Result<MappingResponse> {
-    return query_port(n, 0, 0, false);
+// Function to print the ports written by a daemon for each mapping of every tunnel.
+fn status(configs: &[Config]) -> Result<()> {
+    for config in configs {
+        status_of(config).map_err(|e| tunnel_error(config, e))?;
+    }
+    Ok(())
 }
 
-// Function to request or renew a port mapping.
-fn query_port(
-    n: &mut Natpmp,
-    internal: u16,
-    external: u16,
-    check: bool,
-) -> Result<MappingResponse> {
-    let mut timeout = 250;
-    while timeout <= 64000 {
-        // Send a port mapping request.
-        let _ = n.send_port_mapping_request(Protocol::TCP, internal, external, 360)
-            .map_err(|err| anyhow!("Failed to send port mapping request: {:?}", err));
-        println!("Port mapping request sent! (will timeout in {}ms)", timeout);
-
-        // Wait for a response or timeout.
-        thread::sleep(Duration::from_millis(timeout));
-        match n.read_response_or_retry() {
-            Err(e) => {
-                println!("Failed to read NAT-PMP response: {:?}", e);
-                if let Error::NATPMP_TRYAGAIN = e {
-                    println!("Retry suggested by NAT-PMP. Trying again after a delay.");
-                    thread::sleep(Duration::from_millis(timeout));
-                } else {
-                    return Err(anyhow!("Error reading NAT-PMP response: {:?}", e));
-                }
-            },
-            Ok(response) => {
-                match response {
-                    Response::TCP(tr) => {
-                        println!(
-                            "Received TCP mapping response: Internal: {}, External: {}, Lifetime: {}s",
-                            tr.private_port(),
-                            tr.public_port(),
-                            tr.lifetime().as_secs()
-                        );
-                        // Verify if the response matches the requested mapping, if applicable.
-                        if !check
-                            || (tr.private_port() == internal
-                                && tr.public_port() == external
-                                && tr.lifetime().as_secs() > 0)
-                        {
-                            return Ok(tr);
-                        } else {
-                            println!("Received port does not match requested parameters. Retrying...");
-                        }
-                    },
-                    Response::UDP(ur) => {
-                        println!(
-                            "Received UDP mapping response (unexpected): Internal: {}, External: {}, Lifetime: {}s",
-                            ur.private_port(),
-                            ur.public_port(),
-                            ur.lifetime().as_secs()
-                        );
-                    },
-                    Response::Gateway(gr) => {
-                        println!(
-                            "Received public address response (unexpected): IP: {}, Epoch: {}",
-                            gr.public_address(),
-                            gr.epoch()
-                        );
-                    },
-                }
-            }
+fn status_of(config: &Config) -> Result<()> {
+    let outputs = outputs(config)?;
+    // Read the first output whose format names the mappings.
+    let spec = outputs
+        .iter()
+        .find(|spec| !matches!(spec.format, Format::Env | Format::Port))
+        .unwrap_or(&outputs[0]);
+    let current = output::read(spec)?;
+    if current.is_empty() {
+        bail!("No mapping found in {:?}", spec.path);
+    }
+    let tunnel = config.tunnel.as_ref().map(|t| format!("{}/", t)).unwrap_or_default();
+    for m in current {
+        let state = if Path::new("/proc").join(m.pid.to_string()).exists() {
+            "running"
+        } else {
+            "dead"
         };
-        // Increase timeout for the next attempt.
-        timeout *= 2;
+        let port = |p: Option<u16>| p.map(|p| p.to_string()).unwrap_or("-".to_owned());
+        let public_ip = m.public_ip.map(|ip| format!(", public IP {}", ip)).unwrap_or_default();
+        println!(
+            "{}{}: TCP {}, UDP {}{} (PID {} {})",
+            tunnel,
+            m.name,
+            port(m.tcp),
+            port(m.udp),
+            public_ip,
+            m.pid,
+            state
+        );
+    }
+    Ok(())
+}
+
+// Function to validate the configuration file and print the resulting settings.
+fn check_config(cli: &Cli, configs: &[Config]) -> Result<()> {
+    let Some(path) = &cli.config else {
+        bail!("No configuration file given, use --config or NATPMP_CONFIG");
+    };
+    for config in configs {
+        check_specs(&config.mappings).map_err(|e| tunnel_error(config, e))?;
+    }
+    println!("Configuration file {:?} is valid", path);
+    // Hooks and logs are shared by every tunnel.
+    let config = &configs[0];
+    let hooks = [
+        ("created", &config.hooks.created),
+        ("port_changed", &config.hooks.port_changed),
+        ("public_ip_changed", &config.hooks.public_ip_changed),
+        ("lost", &config.hooks.lost),
+    ];
+    for (event, command) in hooks {
+        if let Some(command) = command {
+            println!("hook {} = {:?}", event, command);
+        }
+    }
+    println!("hook timeout = {}s", config.hooks.timeout.as_secs());
+    println!("public IP interval = {}s", config.public_ip_interval.as_secs());
+    println!("renew at = {}", config.renewal.at);
+    println!("renew jitter = {}", config.renewal.jitter);
+    println!("log level = {}", config.log_level.as_str().to_lowercase());
+    for config in configs {
+        if let Some(tunnel) = &config.tunnel {
+            println!("tunnel {}:", tunnel);
+        }
+        println!("gateway = {}", config.gateway);
+        println!("backend = {}", config.backend);
+        if let Some(netns) = &config.netns {
+            println!("netns = {}", netns);
+        }
+        if config.outputs.is_empty() {
+            println!("output = (none)");
+        }
+        for output in &config.outputs {
+            println!("output = {} {:?}", output.format, output.path);
+        }
+        if let Some(history) = &config.history {
+            println!("history = {:?}", history);
+        }
+        if let Some(state_file) = &config.state_file {
+            println!("state file = {:?}", state_file);
+        }
+        for spec in &config.mappings {
+            let public = match (&spec.public_ports, spec.public_port) {
+                (Some(range), 0) => format!(", public ports {}-{}", range.start(), range.end()),
+                (Some(range), port) => {
+                    format!(", public port {} in {}-{}", port, range.start(), range.end())
+                }
+                (None, 0) => String::new(),
+                (None, port) => format!(", public port {}", port),
+            };
+            println!(
+                "mapping {} = {}, internal port {}, lifetime {}s{}, {} other ports",
+                spec.name, spec.protocols, spec.internal_port, spec.lifetime, public, spec.policy
+            );
+        }
     }
-    bail!("Mapping failed after multiple attempts.");
+    Ok(())
 }
@@ -0,0 +1,322 @@
+use crate::backend::Grant;
+use crate::codec::Protocol;
+use anyhow::{anyhow, bail, Error, Result};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+// Lifetime requested from the gateway when a mapping does not specify one.
+pub const DEFAULT_LIFETIME: u32 = 360;
+
+// Which protocols a named mapping forwards.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Protocols {
+    Tcp,
+    Udp,
+    Both,
+}
+
+impl Protocols {
+    // The NAT-PMP protocols to map.
+    pub fn iter(&self) -> impl Iterator<Item = Protocol> {
+        let protocols: &[Protocol] = match self {
+            Protocols::Tcp => &[Protocol::TCP],
+            Protocols::Udp => &[Protocol::UDP],
+            Protocols::Both => &[Protocol::TCP, Protocol::UDP],
+        };
+        protocols.iter().copied()
+    }
+}
+
+impl fmt::Display for Protocols {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocols::Tcp => write!(f, "tcp"),
+            Protocols::Udp => write!(f, "udp"),
+            Protocols::Both => write!(f, "both"),
+        }
+    }
+}
+
+impl FromStr for Protocols {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(Protocols::Tcp),
+            "udp" => Ok(Protocols::Udp),
+            "both" => Ok(Protocols::Both),
+            _ => bail!("Unknown protocol {:?}, expected tcp, udp or both", s),
+        }
+    }
+}
+
+// What to do when the gateway grants another public port than the preferred one, or one
+// outside the allowed range.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum PortPolicy {
+    // Keep the port granted by the gateway.
+    #[default]
+    Accept,
+    // Give it back and suggest another allowed port, a few times.
+    Retry,
+    // Give it back and fail.
+    Fail,
+}
+
+impl fmt::Display for PortPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortPolicy::Accept => write!(f, "accept"),
+            PortPolicy::Retry => write!(f, "retry"),
+            PortPolicy::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+impl FromStr for PortPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "accept" => Ok(PortPolicy::Accept),
+            "retry" => Ok(PortPolicy::Retry),
+            "fail" => Ok(PortPolicy::Fail),
+            _ => bail!("Unknown port policy {:?}, expected accept, retry or fail", s),
+        }
+    }
+}
+
+// A mapping declared by the user, in the form
+// `NAME[:PROTOCOL[:INTERNAL_PORT[:LIFETIME[:PUBLIC_PORT[:POLICY]]]]]`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MappingSpec {
+    pub name: String,
+    pub protocols: Protocols,
+    pub internal_port: u16,
+    pub lifetime: u32,
+    // Public port to ask the gateway for, 0 letting it choose.
+    pub public_port: u16,
+    // Public ports the mapping may get, only the preferred one if unset.
+    pub public_ports: Option<RangeInclusive<u16>>,
+    pub policy: PortPolicy,
+}
+
+impl Default for MappingSpec {
+    fn default() -> Self {
+        MappingSpec {
+            name: "default".to_owned(),
+            protocols: Protocols::Both,
+            internal_port: 0,
+            lifetime: DEFAULT_LIFETIME,
+            public_port: 0,
+            public_ports: None,
+            policy: PortPolicy::Accept,
+        }
+    }
+}
+
+impl MappingSpec {
+    // Public port to suggest first: the preferred one, or the start of the allowed range.
+    pub fn preferred_port(&self) -> u16 {
+        match (&self.public_ports, self.public_port) {
+            (Some(range), 0) => *range.start(),
+            _ => self.public_port,
+        }
+    }
+
+    // Check that the preferred public port is in the allowed range.
+    pub fn check_public_ports(&self) -> Result<()> {
+        if let Some(range) = &self.public_ports {
+            if self.public_port != 0 && !range.contains(&self.public_port) {
+                bail!(
+                    "Public port {} of mapping {} is outside of its range {}-{}",
+                    self.public_port,
+                    self.name,
+                    range.start(),
+                    range.end()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// Function to parse a range of public ports, in the form `FIRST-LAST`.
+pub fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>> {
+    let range = s.split_once('-').and_then(|(first, last)| {
+        let (first, last): (u16, u16) = (first.parse().ok()?, last.parse().ok()?);
+        (first > 0 && first <= last).then_some(first..=last)
+    });
+    range.ok_or_else(|| anyhow!("Invalid port range {:?}, expected FIRST-LAST", s))
+}
+
+impl FromStr for MappingSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(':');
+        let name = parts.next().unwrap_or_default();
+        if name.is_empty() || name.contains(',') {
+            bail!("Invalid mapping name {:?}", name);
+        }
+        let mut spec = MappingSpec {
+            name: name.to_owned(),
+            ..Default::default()
+        };
+        // Empty fields keep their default, so that later ones can be given alone.
+        let mut next = || parts.next().filter(|part| !part.is_empty());
+        if let Some(protocols) = next() {
+            spec.protocols = protocols.parse()?;
+        }
+        if let Some(port) = next() {
+            spec.internal_port = port
+                .parse()
+                .map_err(|_| anyhow!("Invalid internal port {:?} for mapping {}", port, name))?;
+        }
+        if let Some(lifetime) = next() {
+            spec.lifetime = lifetime
+                .parse()
+                .ok()
+                .filter(|&l| l > 0)
+                .ok_or_else(|| anyhow!("Invalid lifetime {:?} for mapping {}", lifetime, name))?;
+        }
+        // A single preferred port, or a range of allowed ports starting with the preferred one.
+        if let Some(public) = next() {
+            if public.contains('-') {
+                spec.public_ports = Some(parse_port_range(public)?);
+            } else {
+                spec.public_port = public
+                    .parse()
+                    .ok()
+                    .filter(|&p| p > 0)
+                    .ok_or_else(|| anyhow!("Invalid public port {:?} for mapping {}", public, name))?;
+            }
+        }
+        if let Some(policy) = next() {
+            spec.policy = policy.parse()?;
+        }
+        if parts.next().is_some() {
+            bail!("Too many fields in mapping {:?}", s);
+        }
+        Ok(spec)
+    }
+}
+
+// Function to check the declared mappings, of which there must be at least one, with
+// distinct names.
+pub fn check_specs(specs: &[MappingSpec]) -> Result<()> {
+    if specs.is_empty() {
+        bail!("No mapping is declared");
+    }
+    for (i, spec) in specs.iter().enumerate() {
+        if specs[..i].iter().any(|other| other.name == spec.name) {
+            bail!("Mapping {} is declared more than once", spec.name);
+        }
+    }
+    Ok(())
+}
+
+// The state of a named mapping as last granted by the gateway.
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    pub spec: MappingSpec,
+    pub tcp: Option<Grant>,
+    pub udp: Option<Grant>,
+    // When this mapping should next be renewed.
+    pub renew_at: Instant,
+    // When the gateway granted the current lifetime.
+    pub granted_at: SystemTime,
+    // Consecutive failed attempts to renew or recreate the mapping. While non-zero, the
+    // mapping is lost and its ports are those it last had.
+    pub failures: u32,
+}
+
+impl Mapping {
+    pub fn new(
+        spec: MappingSpec,
+        tcp: Option<Grant>,
+        udp: Option<Grant>,
+    ) -> Mapping {
+        let mut m = Mapping {
+            spec,
+            tcp,
+            udp,
+            renew_at: Instant::now(),
+            granted_at: SystemTime::now(),
+            failures: 0,
+        };
+        // Renew at half the shortest granted lifetime, unless the forwarder schedules it.
+        m.renew_at += m.lifetime() / 2;
+        m
+    }
+
+    pub fn get(&self, protocol: Protocol) -> Option<&Grant> {
+        match protocol {
+            Protocol::TCP => self.tcp.as_ref(),
+            Protocol::UDP => self.udp.as_ref(),
+        }
+    }
+
+    pub fn public_port(&self, protocol: Protocol) -> Option<u16> {
+        self.get(protocol).map(|g| g.public_port)
+    }
+
+    // Whether the mapping could not be renewed, its ports being no longer forwarded.
+    pub fn is_lost(&self) -> bool {
+        self.failures > 0
+    }
+
+    // Whether another state of the mapping has different public ports.
+    pub fn ports_differ_from(&self, other: &Mapping) -> bool {
+        [Protocol::TCP, Protocol::UDP]
+            .into_iter()
+            .any(|p| self.public_port(p) != other.public_port(p))
+    }
+
+    // Epochs reported by the gateway in the granted mappings.
+    pub fn epochs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.tcp.iter().chain(self.udp.iter()).map(|g| g.epoch)
+    }
+
+    // Whether both protocols are mapped on different public ports.
+    pub fn ports_differ(&self) -> bool {
+        match (&self.tcp, &self.udp) {
+            (Some(tcp), Some(udp)) => tcp.public_port != udp.public_port,
+            _ => false,
+        }
+    }
+
+    // Shortest lifetime of the granted mappings, which drives the renewal.
+    pub fn lifetime(&self) -> Duration {
+        self.tcp
+            .iter()
+            .chain(self.udp.iter())
+            .map(|g| g.lifetime)
+            .min()
+            .unwrap_or_default()
+    }
+
+    // When the mapping expires on the gateway unless it is renewed.
+    pub fn expires_at(&self) -> SystemTime {
+        self.granted_at + self.lifetime()
+    }
+
+    // Public address the gateway granted the mapping on, which only PCP gateways tell.
+    pub fn public_ip(&self) -> Option<Ipv4Addr> {
+        self.tcp.iter().chain(self.udp.iter()).find_map(|g| g.public_ip)
+    }
+
+    // Latest epoch reported by the gateway for this mapping.
+    pub fn epoch(&self) -> Option<u32> {
+        self.epochs().max()
+    }
+
+    // The main public port, the TCP one or the UDP one for UDP-only mappings.
+    pub fn port(&self) -> Option<u16> {
+        self.public_port(Protocol::TCP).or(self.public_port(Protocol::UDP))
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::{bail, Context, Error, Result};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Directory where `ip netns add` mounts the named network namespaces.
+pub const NETNS_DIR: &str = "/run/netns";
+
+// Network namespace of the calling thread, which setns changes.
+const THREAD_NETNS: &str = "/proc/thread-self/ns/net";
+
+// A network namespace to create the gateway sockets in, given as a name under /run/netns
+// or as a path such as /proc/PID/ns/net.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NetnsSpec {
+    pub path: PathBuf,
+}
+
+impl FromStr for NetnsSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() || s == "." || s == ".." {
+            bail!("Invalid network namespace {:?}", s);
+        }
+        let path = if s.contains('/') {
+            PathBuf::from(s)
+        } else {
+            Path::new(NETNS_DIR).join(s)
+        };
+        Ok(NetnsSpec { path })
+    }
+}
+
+impl fmt::Display for NetnsSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+// Function to run `f` with the calling thread in the given network namespace, if any,
+// switching back to the current one afterwards. Sockets created by `f` stay in the
+// namespace, while the files and processes of the daemon keep the view of the host.
+pub fn enter<T>(netns: Option<&NetnsSpec>, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let Some(netns) = netns else {
+        return f();
+    };
+    let current = File::open(THREAD_NETNS).with_context(|| {
+        format!(
+            "Failed to open the current network namespace {}",
+            THREAD_NETNS
+        )
+    })?;
+    let target = File::open(&netns.path)
+        .with_context(|| format!("Failed to open network namespace {}", netns))?;
+    setns(&target).with_context(|| {
+        format!(
+            "Failed to enter network namespace {} (CAP_SYS_ADMIN is required)",
+            netns
+        )
+    })?;
+    let result = f();
+    if let Err(e) = setns(&current) {
+        // Going on would leave the thread, and everything it runs later, in the namespace.
+        panic!("Failed to leave network namespace {}: {}", netns, e);
+    }
+    result
+}
+
+fn setns(file: &File) -> io::Result<()> {
+    // SAFETY: the descriptor is open for the duration of the call.
+    if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,373 @@
+use crate::codec::Protocol;
+use crate::mapping::Mapping;
+use anyhow::{bail, Context, Error, Result};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+// Formats in which the daemon can publish its mappings.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Format {
+    // `Name,PID,TCPPort,UDPPort,PublicIP` lines, without header.
+    Plain,
+    // CSV with a header row and the time each mapping was granted.
+    Csv,
+    // JSON object with the PID, public IP and the details of every mapping.
+    Json,
+    // Shell-sourceable `KEY=value` lines.
+    Env,
+    // The bare main port of every mapping, one per line, for tools which read a number.
+    Port,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Plain => write!(f, "plain"),
+            Format::Csv => write!(f, "csv"),
+            Format::Json => write!(f, "json"),
+            Format::Env => write!(f, "env"),
+            Format::Port => write!(f, "port"),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "plain" => Ok(Format::Plain),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "env" => Ok(Format::Env),
+            "port" => Ok(Format::Port),
+            _ => bail!("Unknown output format {:?}, expected plain, csv, json, env or port", s),
+        }
+    }
+}
+
+// An output file declared by the user, in the form `[FORMAT:]PATH`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OutputSpec {
+    pub format: Format,
+    pub path: PathBuf,
+}
+
+impl FromStr for OutputSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // Paths without a known format prefix are written in the plain format.
+        let (format, path) = match s.split_once(':') {
+            Some((format, path)) => match format.parse() {
+                Ok(format) => (format, path),
+                Err(_) => (Format::Plain, s),
+            },
+            None => (Format::Plain, s),
+        };
+        if path.is_empty() {
+            bail!("Missing path in output {:?}", s);
+        }
+        Ok(OutputSpec {
+            format,
+            path: path.into(),
+        })
+    }
+}
+
+impl fmt::Display for OutputSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.format, self.path.display())
+    }
+}
+
+// Files where the daemon publishes the ports of its mappings: the output files, which
+// always hold the current state, and an optional history where every change is appended.
+pub struct Output {
+    specs: Vec<OutputSpec>,
+    history: Option<File>,
+}
+
+impl Output {
+    pub fn create(specs: &[OutputSpec], history: Option<&Path>) -> Result<Output> {
+        let history = match history {
+            Some(history) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(history)
+                    .with_context(|| format!("Failed to open history file {:?}", history))?,
+            ),
+            None => None,
+        };
+        Ok(Output {
+            specs: specs.to_vec(),
+            history,
+        })
+    }
+
+    // Replace every output file with the current state of the mappings.
+    pub fn write(&self, mappings: &[Mapping], public_ip: Ipv4Addr) -> Result<()> {
+        for spec in &self.specs {
+            let content = render(spec.format, mappings, public_ip)?;
+            replace(&spec.path, &content)?;
+        }
+        Ok(())
+    }
+
+    // Append the new state of a mapping to the history file, if any.
+    pub fn record(&mut self, m: &Mapping, public_ip: Ipv4Addr) -> Result<()> {
+        if let Some(history) = &mut self.history {
+            history
+                .write_all(plain_line(m, public_ip).as_bytes())
+                .context("Failed to append to history file")?;
+        }
+        Ok(())
+    }
+
+    // Remove the output files which were written, the history is kept.
+    pub fn remove(self) -> Result<()> {
+        for spec in &self.specs {
+            match fs::remove_file(&spec.path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to remove output file {:?}", spec.path));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Function to replace a file with the given content. The content is written to a
+// temporary file which is renamed over the target, so that readers never see a
+// partially written file.
+pub(crate) fn replace(path: &Path, content: &str) -> Result<()> {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+
+    let mut file =
+        File::create(&tmp).with_context(|| format!("Failed to create temporary file {:?}", tmp))?;
+    file.write_all(content.as_bytes())
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Failed to write temporary file {:?}", tmp))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace file {:?}", path))?;
+    // Persist the rename itself, which lives in the parent directory.
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent).and_then(|dir| dir.sync_all())?;
+    Ok(())
+}
+
+// Content of the JSON format.
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonState {
+    pid: u32,
+    public_ip: Ipv4Addr,
+    // `ok`, or `degraded` while some mappings are lost.
+    status: String,
+    mappings: Vec<JsonMapping>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonMapping {
+    name: String,
+    lost: bool,
+    ports: JsonPorts,
+    lifetime: u64,
+    expires_at: String,
+    epoch: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonPorts {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp: Option<u16>,
+}
+
+// Function to get the TCP and UDP ports of a mapping, which are invalid once it is lost.
+fn ports(m: &Mapping) -> (Option<u16>, Option<u16>) {
+    if m.is_lost() {
+        return (None, None);
+    }
+    (m.public_port(Protocol::TCP), m.public_port(Protocol::UDP))
+}
+
+// Function to format the mappings in the given format.
+fn render(format: Format, mappings: &[Mapping], public_ip: Ipv4Addr) -> Result<String> {
+    let pid = process::id();
+    let port = |p: Option<u16>| p.map(|p| p.to_string()).unwrap_or_default();
+    let status = if mappings.iter().any(Mapping::is_lost) {
+        "degraded"
+    } else {
+        "ok"
+    };
+    let content = match format {
+        Format::Plain => mappings.iter().map(|m| plain_line(m, public_ip)).collect(),
+        Format::Csv => {
+            let mut content = "Timestamp,Name,PID,TCPPort,UDPPort,PublicIP\n".to_owned();
+            for m in mappings {
+                let (tcp, udp) = ports(m);
+                content += &format!(
+                    "{},{},{},{},{},{}\n",
+                    timestamp(m.granted_at)?,
+                    m.spec.name,
+                    pid,
+                    port(tcp),
+                    port(udp),
+                    public_ip
+                );
+            }
+            content
+        }
+        Format::Json => {
+            let mut state = JsonState {
+                pid,
+                public_ip,
+                status: status.to_owned(),
+                mappings: Vec::with_capacity(mappings.len()),
+            };
+            for m in mappings {
+                let (tcp, udp) = ports(m);
+                state.mappings.push(JsonMapping {
+                    name: m.spec.name.clone(),
+                    lost: m.is_lost(),
+                    ports: JsonPorts { tcp, udp },
+                    lifetime: m.lifetime().as_secs(),
+                    expires_at: timestamp(m.expires_at())?,
+                    epoch: m.epoch().unwrap_or_default(),
+                });
+            }
+            serde_json::to_string_pretty(&state)? + "\n"
+        }
+        Format::Env => {
+            let mut content = format!(
+                "NATPMP_PID={}\nNATPMP_PUBLIC_IP={}\nNATPMP_STATUS={}\n",
+                pid, public_ip, status
+            );
+            for m in mappings {
+                let prefix = env_prefix(&m.spec.name);
+                let (tcp, udp) = ports(m);
+                for (key, port) in [("PORT", tcp.or(udp)), ("TCP_PORT", tcp), ("UDP_PORT", udp)] {
+                    if let Some(port) = port {
+                        content += &format!("{}_{}={}\n", prefix, key, port);
+                    }
+                }
+            }
+            content
+        }
+        Format::Port => mappings
+            .iter()
+            .filter_map(|m| {
+                let (tcp, udp) = ports(m);
+                tcp.or(udp)
+            })
+            .map(|p| format!("{}\n", p))
+            .collect(),
+    };
+    Ok(content)
+}
+
+// Function to format a mapping as a `Name,PID,TCPPort,UDPPort,PublicIP` line.
+fn plain_line(m: &Mapping, public_ip: Ipv4Addr) -> String {
+    // Ports of protocols which are not forwarded, or of lost mappings, are left empty.
+    let port = |p: Option<u16>| p.map(|p| p.to_string()).unwrap_or_default();
+    let (tcp, udp) = ports(m);
+    format!(
+        "{},{},{},{},{}\n",
+        m.spec.name,
+        process::id(),
+        port(tcp),
+        port(udp),
+        public_ip
+    )
+}
+
+// Function to format a time as an RFC 3339 timestamp, in UTC.
+pub(crate) fn timestamp(time: SystemTime) -> Result<String> {
+    Ok(Timestamp::try_from(time)?.round(jiff::Unit::Second)?.to_string())
+}
+
+// Function to build the variable prefix of a mapping, such as `NATPMP_QBITTORRENT`.
+fn env_prefix(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("NATPMP_{}", name)
+}
+
+// A mapping as published by a running daemon.
+#[derive(Debug)]
+pub struct Published {
+    pub name: String,
+    pub pid: u32,
+    pub tcp: Option<u16>,
+    pub udp: Option<u16>,
+    pub public_ip: Option<Ipv4Addr>,
+}
+
+// Function to read the mappings published in an output file, for formats which name them.
+pub fn read(spec: &OutputSpec) -> Result<Vec<Published>> {
+    let content = fs::read_to_string(&spec.path)
+        .with_context(|| format!("Daemon is not running, cannot read {:?}", spec.path))?;
+    let port = |p: &str| p.parse().ok();
+    let mut published = vec![];
+    match spec.format {
+        Format::Plain | Format::Csv => {
+            let skip = if spec.format == Format::Csv { 1 } else { 0 };
+            for line in content.lines().skip(skip) {
+                let mut fields: Vec<&str> = line.split(',').collect();
+                if spec.format == Format::Csv && !fields.is_empty() {
+                    fields.remove(0);
+                }
+                // Files written before the public IP was published lack it.
+                let (name, pid, tcp, udp, public_ip) = match fields[..] {
+                    [name, pid, tcp, udp] => (name, pid, tcp, udp, None),
+                    [name, pid, tcp, udp, public_ip] => (name, pid, tcp, udp, public_ip.parse().ok()),
+                    _ => continue,
+                };
+                published.push(Published {
+                    name: name.to_owned(),
+                    pid: pid.parse().unwrap_or_default(),
+                    tcp: port(tcp),
+                    udp: port(udp),
+                    public_ip,
+                });
+            }
+        }
+        Format::Json => {
+            let state: JsonState = serde_json::from_str(&content)
+                .with_context(|| format!("Invalid JSON in {:?}", spec.path))?;
+            for m in state.mappings {
+                published.push(Published {
+                    name: m.name,
+                    pid: state.pid,
+                    tcp: m.ports.tcp,
+                    udp: m.ports.udp,
+                    public_ip: Some(state.public_ip),
+                });
+            }
+        }
+        Format::Env | Format::Port => {
+            bail!("Output {} does not name the mappings, use a plain, csv or json one", spec)
+        }
+    }
+    Ok(published)
+}
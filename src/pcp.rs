@@ -0,0 +1,389 @@
+use crate::backend::{
+    is_refused, Backend, BoxFuture, GatewayError, Grant, PublicAddress, Session,
+    PROBE_TIMEOUTS,
+};
+use crate::codec::Protocol;
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+
+// Version of the protocol, NAT-PMP being version 0 (RFC 6887 9).
+const VERSION: u8 = 2;
+const OPCODE_ANNOUNCE: u8 = 0;
+const OPCODE_MAP: u8 = 1;
+// Bit of the opcode set in responses.
+const RESPONSE: u8 = 0x80;
+const HEADER_LEN: usize = 24;
+const MAP_LEN: usize = 36;
+// Largest PCP message (RFC 6887 7).
+const MAX_LEN: usize = 1100;
+
+// Retransmission of requests (RFC 6887 8.1.1): the first attempt times out after IRT, each
+// next one after twice the previous timeout up to MRT, every timeout being randomized by
+// up to 10%. PCP clients retry forever, but the forwarder gives up after a few attempts to
+// look the gateway up again.
+const IRT: Duration = Duration::from_secs(3);
+const MRT: Duration = Duration::from_secs(1024);
+const MAX_ATTEMPTS: usize = 6;
+
+// Ports picked for mappings of internal port 0 when the gateway may choose the public one.
+const DYNAMIC_PORTS: RangeInclusive<u16> = 49152..=65535;
+// Requests to get the same internal and public ports for a mapping of internal port 0.
+const MAX_ALIGN_ATTEMPTS: usize = 3;
+
+// Port Control Protocol client (RFC 6887), asking for mappings with MAP requests and for
+// the epoch of the gateway with ANNOUNCE ones. Only IPv4 mappings are requested.
+pub struct Pcp {
+    socket: UdpSocket,
+    gateway: Ipv4Addr,
+    // Address of this host as seen by the gateway, which every request carries.
+    client_ip: Ipv4Addr,
+    // Nonces of the granted mappings, kept by the forwarder across reconnections.
+    session: Session,
+    // Nonces of the mappings requested but not granted yet, which the retries repeat.
+    pending: HashMap<(Protocol, u16), [u8; 12]>,
+}
+
+// A response of the gateway, with the payload following the header.
+struct Response {
+    version: u8,
+    opcode: u8,
+    result: u8,
+    lifetime: u32,
+    epoch: u32,
+    payload: Vec<u8>,
+}
+
+impl Pcp {
+    // Speak PCP over a socket connected to the gateway, with the nonces of the session.
+    pub fn new(socket: UdpSocket, gateway: Ipv4Addr, session: Session) -> Result<Pcp> {
+        let client_ip = match socket.local_addr()? {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => bail!("PCP is only supported over IPv4"),
+        };
+        Ok(Pcp {
+            socket,
+            gateway,
+            client_ip,
+            session,
+            pending: HashMap::new(),
+        })
+    }
+
+    // Give the socket back, to speak NAT-PMP over it.
+    pub fn into_socket(self) -> UdpSocket {
+        self.socket
+    }
+
+    // Find out whether the gateway speaks PCP, with an ANNOUNCE request. A NAT-PMP gateway
+    // answers it with version 0 (RFC 6887 9), while others may not answer at all.
+    pub async fn probe(&mut self) -> Result<bool> {
+        match self.send_probe().await {
+            Err(e) if is_refused(&e) => {
+                info!("Gateway {} does not listen on the PCP port", self.gateway);
+                Ok(false)
+            }
+            result => result,
+        }
+    }
+
+    async fn send_probe(&mut self) -> Result<bool> {
+        let request = self.header(OPCODE_ANNOUNCE, 0);
+        for timeout in PROBE_TIMEOUTS {
+            self.send(&request).await?;
+            debug!("PCP probe sent! (will timeout in {}ms)", timeout);
+            let deadline = time::Instant::now() + Duration::from_millis(timeout);
+            while let Some(response) = self.receive(deadline).await? {
+                if response.version == VERSION {
+                    return Ok(true);
+                }
+                if response.version == 0 {
+                    info!("Gateway {} answered PCP with NAT-PMP", self.gateway);
+                    return Ok(false);
+                }
+                debug!(
+                    "Received PCP response of version {} (unexpected)",
+                    response.version
+                );
+            }
+        }
+        info!("Gateway {} does not answer PCP", self.gateway);
+        Ok(false)
+    }
+
+    async fn announce(&mut self) -> Result<PublicAddress> {
+        let request = self.header(OPCODE_ANNOUNCE, 0);
+        let response = self.request(&request, OPCODE_ANNOUNCE, |_| true).await?;
+        info!("Got ANNOUNCE response: Epoch: {}", response.epoch);
+        Ok(PublicAddress {
+            ip: None,
+            epoch: response.epoch,
+        })
+    }
+
+    async fn request_port(
+        &mut self,
+        protocol: Protocol,
+        internal: u16,
+        external: u16,
+        lifetime: u32,
+    ) -> Result<Grant> {
+        if internal != 0 || lifetime == 0 {
+            return self.send_map(protocol, internal, external, lifetime).await;
+        }
+        // A MAP request needs an internal port (RFC 6887 11.1). Like NAT-PMP gateways such as
+        // ProtonVPN do for port 0, map the public port to the same local port, moving to the
+        // public port the gateway grants when it is another one.
+        let mut port = match external {
+            0 => fastrand::u16(DYNAMIC_PORTS),
+            port => port,
+        };
+        for _ in 0..MAX_ALIGN_ATTEMPTS {
+            let grant = self.send_map(protocol, port, port, lifetime).await?;
+            if grant.public_port == port || grant.lifetime.is_zero() {
+                return Ok(grant);
+            }
+            debug!(
+                "Gateway granted {:?} public port {} for internal port {}, moving to it",
+                protocol, grant.public_port, port
+            );
+            self.send_map(protocol, port, 0, 0).await?;
+            port = grant.public_port;
+        }
+        bail!(
+            "Gateway did not grant {:?} public ports equal to the internal ones",
+            protocol
+        );
+    }
+
+    // Send a MAP request until the gateway replies for the same mapping.
+    async fn send_map(
+        &mut self,
+        protocol: Protocol,
+        internal: u16,
+        external: u16,
+        lifetime: u32,
+    ) -> Result<Grant> {
+        let number = protocol_number(protocol);
+        let granted = self.session.nonce(protocol, internal);
+        let nonce = granted.unwrap_or_else(|| {
+            *self
+                .pending
+                .entry((protocol, internal))
+                .or_insert_with(|| std::array::from_fn(|_| fastrand::u8(..)))
+        });
+        let mut request = self.header(OPCODE_MAP, lifetime);
+        request.extend(nonce);
+        request.extend([number, 0, 0, 0]);
+        request.extend(internal.to_be_bytes());
+        request.extend(external.to_be_bytes());
+        request.extend(Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+        debug!(
+            "{:?} MAP request: Internal: {}, External: {}, Lifetime: {}s",
+            protocol, internal, external, lifetime
+        );
+
+        // Replies to a request for another mapping may arrive late.
+        let matches = |payload: &[u8]| {
+            payload.len() >= MAP_LEN
+                && payload[..12] == nonce
+                && payload[12] == number
+                && payload[16..18] == internal.to_be_bytes()
+        };
+        let response = match self.request(&request, OPCODE_MAP, matches).await {
+            // The mapping may be held with another nonce, as one created by a previous run,
+            // until it expires. Without a sign of that, the gateway refuses every mapping.
+            Err(e)
+                if granted.is_none()
+                    && self.session.is_held(protocol, internal)
+                    && GatewayError::find(&e) == Some(GatewayError::NotAuthorized) =>
+            {
+                bail!(
+                    "Gateway refused the {:?} mapping of internal port {} (NOTAUTHORIZED), which may be held with another nonce until it expires",
+                    protocol,
+                    internal
+                );
+            }
+            // The gateway may have created the mapping with the pending nonce, which is lost
+            // if the forwarder connects again.
+            Err(e) if GatewayError::find(&e).is_none() && lifetime != 0 => {
+                self.session.set_held(protocol, internal);
+                return Err(e);
+            }
+            response => response?,
+        };
+        let payload = &response.payload;
+        let public_port = u16::from_be_bytes([payload[18], payload[19]]);
+        let address: [u8; 16] = payload[20..36].try_into().unwrap();
+        let public_ip = Ipv6Addr::from(address).to_ipv4_mapped();
+        info!(
+            "Received {:?} MAP response: Internal: {}, External: {}, Lifetime: {}s, Epoch: {}",
+            protocol, internal, public_port, response.lifetime, response.epoch
+        );
+        self.pending.remove(&(protocol, internal));
+        if lifetime == 0 {
+            self.session.remove_nonce(protocol, internal);
+        } else {
+            self.session.set_nonce(protocol, internal, nonce);
+        }
+        Ok(Grant {
+            private_port: internal,
+            public_port,
+            lifetime: Duration::from_secs(response.lifetime.into()),
+            epoch: response.epoch,
+            public_ip: public_ip.filter(|ip| !ip.is_unspecified()),
+        })
+    }
+
+    // Send a request until the gateway replies to it with success, retrying on network
+    // failures. Other result codes are returned as errors.
+    async fn request(
+        &mut self,
+        request: &[u8],
+        opcode: u8,
+        matches: impl Fn(&[u8]) -> bool,
+    ) -> Result<Response> {
+        // Drop the replies left over from previous requests, such as the duplicates of a
+        // retransmitted one, which ANNOUNCE responses cannot be told apart from.
+        let mut buf = [0; MAX_LEN];
+        while self.socket.try_recv(&mut buf).is_ok() {}
+        let mut failure = None;
+        let mut timeout = IRT;
+        for _ in 0..MAX_ATTEMPTS {
+            let randomized = timeout.mul_f64(1.0 + (fastrand::f64() * 0.2 - 0.1));
+            self.send(request).await?;
+            debug!("PCP request sent! (will timeout in {}ms)", randomized.as_millis());
+            let deadline = time::Instant::now() + randomized;
+            timeout = (timeout * 2).min(MRT);
+            while let Some(response) = self.receive(deadline).await? {
+                if response.version != VERSION {
+                    // The gateway went back to NAT-PMP, as after a firmware change.
+                    return Err(GatewayError::UnsupportedVersion.into());
+                }
+                if response.opcode != opcode || !matches(&response.payload) {
+                    debug!(
+                        "Received PCP response (unexpected): Opcode: {}",
+                        response.opcode
+                    );
+                    continue;
+                }
+                match result_error(response.result) {
+                    None => return Ok(response),
+                    Some(GatewayError::NetworkFailure) => {
+                        debug!("Gateway reported a network failure, retrying...");
+                        failure = Some(GatewayError::NetworkFailure);
+                    }
+                    Some(e) => return Err(e.into()),
+                }
+            }
+        }
+        if let Some(e) = failure {
+            return Err(e.into());
+        }
+        bail!("No PCP response from gateway {}", self.gateway);
+    }
+
+    // Header of a request, with the client address mapped to IPv6 (RFC 6887 7.1).
+    fn header(&self, opcode: u8, lifetime: u32) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN + MAP_LEN);
+        header.extend([VERSION, opcode, 0, 0]);
+        header.extend(lifetime.to_be_bytes());
+        header.extend(self.client_ip.to_ipv6_mapped().octets());
+        header
+    }
+
+    async fn send(&self, request: &[u8]) -> Result<()> {
+        self.socket
+            .send(request)
+            .await
+            .with_context(|| format!("Failed to send PCP request to {}", self.gateway))?;
+        Ok(())
+    }
+
+    // Wait for the next response until the deadline, returning `None` once it expires.
+    // Responses of NAT-PMP gateways only carry a version, opcode and result code.
+    async fn receive(&self, deadline: time::Instant) -> Result<Option<Response>> {
+        let mut buf = [0; MAX_LEN];
+        loop {
+            let len = match time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                Err(_) => return Ok(None),
+                Ok(len) => len.context("Failed to receive PCP response")?,
+            };
+            let packet = &buf[..len];
+            let valid = match packet.first() {
+                Some(0) => len >= 4,
+                Some(_) => len >= HEADER_LEN,
+                None => false,
+            };
+            if !valid || packet[1] & RESPONSE == 0 {
+                debug!("Ignoring invalid PCP response: {:02x?}", packet);
+                continue;
+            }
+            let word = |i: usize| {
+                packet
+                    .get(i..i + 4)
+                    .map_or(0, |w| u32::from_be_bytes(w.try_into().unwrap()))
+            };
+            return Ok(Some(Response {
+                version: packet[0],
+                opcode: packet[1] & !RESPONSE,
+                result: packet[3],
+                lifetime: word(4),
+                epoch: word(8),
+                payload: packet.get(HEADER_LEN..).unwrap_or_default().to_vec(),
+            }));
+        }
+    }
+}
+
+impl Backend for Pcp {
+    fn name(&self) -> &'static str {
+        "PCP"
+    }
+
+    fn gateway(&self) -> Ipv4Addr {
+        self.gateway
+    }
+
+    fn public_address(&mut self) -> BoxFuture<'_, Result<PublicAddress>> {
+        Box::pin(self.announce())
+    }
+
+    fn map(
+        &mut self,
+        protocol: Protocol,
+        internal: u16,
+        external: u16,
+        lifetime: u32,
+    ) -> BoxFuture<'_, Result<Grant>> {
+        Box::pin(self.request_port(protocol, internal, external, lifetime))
+    }
+}
+
+// Function to get the IANA number of a protocol.
+fn protocol_number(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::TCP => 6,
+        Protocol::UDP => 17,
+    }
+}
+
+// Function to get the error behind a PCP result code (RFC 6887 7.4), if it is not a success.
+fn result_error(result: u8) -> Option<GatewayError> {
+    match result {
+        0 => None,
+        1 => Some(GatewayError::UnsupportedVersion),
+        2 => Some(GatewayError::NotAuthorized),
+        // UNSUPP_OPCODE, UNSUPP_PROTOCOL
+        4 | 9 => Some(GatewayError::UnsupportedOpcode),
+        7 => Some(GatewayError::NetworkFailure),
+        // NO_RESOURCES, USER_EX_QUOTA
+        8 | 10 => Some(GatewayError::OutOfResources),
+        _ => Some(GatewayError::Undefined),
+    }
+}
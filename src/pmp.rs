@@ -0,0 +1,272 @@
+use crate::backend::{
+    Backend, BoxFuture, GatewayError, Grant, PublicAddress, PROBE_TIMEOUTS, RETRY_TIMEOUTS,
+};
+use crate::codec::{Body, Protocol, Request, Response, SUCCESS};
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::time;
+
+// Largest packet read from the socket, so that longer ones are seen as invalid instead of
+// being cut down to a valid length.
+const MAX_LEN: usize = 1100;
+
+// NAT-PMP client (RFC 6886) over a socket connected to the gateway. Several requests may be
+// in flight at once: whichever of them reads a response hands it to the request it answers,
+// matched by opcode and internal port.
+pub struct NatPmp {
+    socket: UdpSocket,
+    gateway: Ipv4Addr,
+    pending: Mutex<Pending>,
+    // Woken when a response is handed to a request.
+    delivered: Notify,
+}
+
+// Requests in flight, by id, with the response once read.
+#[derive(Default)]
+struct Pending {
+    next_id: u64,
+    requests: Vec<(u64, Request, Option<Response>)>,
+}
+
+// Removes a request from those in flight when it completes or is cancelled.
+struct Registration<'a> {
+    n: &'a NatPmp,
+    id: u64,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.n.pending.lock().unwrap();
+        pending.requests.retain(|(id, _, _)| *id != self.id);
+    }
+}
+
+impl NatPmp {
+    // Speak NAT-PMP over a socket connected to the gateway.
+    pub fn new(socket: UdpSocket, gateway: Ipv4Addr) -> NatPmp {
+        NatPmp {
+            socket,
+            gateway,
+            pending: Mutex::new(Pending::default()),
+            delivered: Notify::new(),
+        }
+    }
+
+    // Find out whether the gateway speaks NAT-PMP, with a public address request. Any result
+    // code is an answer, while socket errors, such as ICMP port unreachable, are not.
+    pub async fn probe(&self) -> bool {
+        match self.exchange(Request::PublicAddress, &PROBE_TIMEOUTS).await {
+            Ok(Some(_)) => true,
+            Err(e) if GatewayError::find(&e).is_some() => true,
+            Ok(None) | Err(_) => {
+                info!("Gateway {} does not answer NAT-PMP", self.gateway);
+                false
+            }
+        }
+    }
+
+    // Query the public address and epoch of the gateway.
+    pub async fn public_address(&self) -> Result<PublicAddress> {
+        let response = self.request(Request::PublicAddress).await?;
+        let Body::PublicAddress(ip) = response.body else {
+            unreachable!("Successful responses carry every field");
+        };
+        info!("Got response: IP: {}, Epoch: {}", ip, response.epoch);
+        Ok(PublicAddress {
+            ip: Some(ip),
+            epoch: response.epoch,
+        })
+    }
+
+    // Create, renew or, with a zero lifetime, delete the mapping of an internal port.
+    pub async fn map(
+        &self,
+        protocol: Protocol,
+        internal: u16,
+        external: u16,
+        lifetime: u32,
+    ) -> Result<Grant> {
+        let request = Request::Map {
+            protocol,
+            internal,
+            external,
+            lifetime,
+        };
+        let response = self.request(request).await?;
+        let Body::Map {
+            internal,
+            external,
+            lifetime,
+        } = response.body
+        else {
+            unreachable!("Successful responses carry every field");
+        };
+        info!(
+            "Received {:?} mapping response: Internal: {}, External: {}, Lifetime: {}s, Epoch: {}",
+            protocol, internal, external, lifetime, response.epoch
+        );
+        Ok(Grant {
+            private_port: internal,
+            public_port: external,
+            lifetime: Duration::from_secs(lifetime.into()),
+            epoch: response.epoch,
+            public_ip: None,
+        })
+    }
+
+    // Send a request until the gateway answers it with success. A network failure is only
+    // recorded, the request being sent again, while other result codes are returned as
+    // errors.
+    async fn request(&self, request: Request) -> Result<Response> {
+        match self.exchange(request, &RETRY_TIMEOUTS).await? {
+            Some(response) => Ok(response),
+            None if matches!(request, Request::PublicAddress) => bail!("Querying gateway failed!"),
+            None => bail!("Mapping failed after multiple attempts."),
+        }
+    }
+
+    // Send a request at each timeout until the gateway answers it, returning `None` if it
+    // never does.
+    async fn exchange(&self, request: Request, timeouts: &[u64]) -> Result<Option<Response>> {
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.next_id += 1;
+            let id = pending.next_id;
+            pending.requests.push((id, request, None));
+            id
+        };
+        let _registration = Registration { n: self, id };
+        let packet = request.encode();
+        let mut failure = None;
+        for &timeout in timeouts {
+            self.socket
+                .send(&packet)
+                .await
+                .with_context(|| format!("Failed to send NAT-PMP request to {}", self.gateway))?;
+            debug!(
+                "{:?} request sent! (will timeout in {}ms)",
+                request.opcode(),
+                timeout
+            );
+            let deadline = time::Instant::now() + Duration::from_millis(timeout);
+            while let Some(response) = self.receive(id, deadline).await? {
+                match result_error(response.result) {
+                    None => return Ok(Some(response)),
+                    Some(GatewayError::NetworkFailure) => {
+                        debug!("Gateway reported a network failure, retrying...");
+                        failure = Some(GatewayError::NetworkFailure);
+                    }
+                    Some(e) => return Err(e.into()),
+                }
+            }
+        }
+        match failure {
+            Some(e) => Err(e.into()),
+            None => Ok(None),
+        }
+    }
+
+    // Wait for the response to a request until the deadline, returning `None` once it
+    // expires. Responses to other requests in flight are handed to them.
+    async fn receive(&self, id: u64, deadline: time::Instant) -> Result<Option<Response>> {
+        let mut buf = [0; MAX_LEN];
+        loop {
+            // Listen for deliveries before looking, so that none is missed.
+            let delivered = self.delivered.notified();
+            tokio::pin!(delivered);
+            delivered.as_mut().enable();
+            if let Some(response) = self.take(id) {
+                return Ok(Some(response));
+            }
+            let received = async {
+                tokio::select! {
+                    len = self.socket.recv(&mut buf) => Some(len),
+                    _ = &mut delivered => None,
+                }
+            };
+            let len = match time::timeout_at(deadline, received).await {
+                Err(_) => return Ok(None),
+                Ok(None) => continue,
+                Ok(Some(len)) => len.context("Failed to receive NAT-PMP response")?,
+            };
+            // Packets of another version, such as a late reply to the PCP ANNOUNCE probe
+            // sent over the same socket, are ignored like other invalid ones: a gateway
+            // which stopped speaking NAT-PMP answers with result code 1 instead.
+            let response = match Response::decode(&buf[..len]) {
+                Ok(response) => response,
+                Err(e) => {
+                    debug!("Ignoring invalid response ({}): {:02x?}", e, &buf[..len]);
+                    continue;
+                }
+            };
+            if !self.deliver(response) {
+                // A late reply to an earlier request, such as a retransmitted one.
+                debug!("Received response (unexpected): {:?}", response);
+            }
+        }
+    }
+
+    // Hand a response to the oldest request in flight it answers, if any.
+    fn deliver(&self, response: Response) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let request = pending
+            .requests
+            .iter_mut()
+            .find(|(_, request, received)| received.is_none() && request.matches(&response));
+        let Some((_, _, received)) = request else {
+            return false;
+        };
+        *received = Some(response);
+        self.delivered.notify_waiters();
+        true
+    }
+
+    fn take(&self, id: u64) -> Option<Response> {
+        let mut pending = self.pending.lock().unwrap();
+        let (_, _, received) = pending.requests.iter_mut().find(|(i, _, _)| *i == id)?;
+        received.take()
+    }
+}
+
+impl Backend for NatPmp {
+    fn name(&self) -> &'static str {
+        "NAT-PMP"
+    }
+
+    fn gateway(&self) -> Ipv4Addr {
+        self.gateway
+    }
+
+    fn public_address(&mut self) -> BoxFuture<'_, Result<PublicAddress>> {
+        Box::pin(NatPmp::public_address(self))
+    }
+
+    fn map(
+        &mut self,
+        protocol: Protocol,
+        internal: u16,
+        external: u16,
+        lifetime: u32,
+    ) -> BoxFuture<'_, Result<Grant>> {
+        Box::pin(NatPmp::map(self, protocol, internal, external, lifetime))
+    }
+}
+
+// Function to get the error behind a NAT-PMP result code (RFC 6886 3.5), if it is not a
+// success.
+fn result_error(result: u16) -> Option<GatewayError> {
+    match result {
+        SUCCESS => None,
+        1 => Some(GatewayError::UnsupportedVersion),
+        2 => Some(GatewayError::NotAuthorized),
+        3 => Some(GatewayError::NetworkFailure),
+        4 => Some(GatewayError::OutOfResources),
+        5 => Some(GatewayError::UnsupportedOpcode),
+        _ => Some(GatewayError::Undefined),
+    }
+}
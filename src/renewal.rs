@@ -0,0 +1,91 @@
+use anyhow::{bail, Error, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+// When to renew a mapping: at a fraction of its granted lifetime, or a margin before it
+// expires.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RenewAt {
+    Fraction(f64),
+    Margin(Duration),
+}
+
+impl Default for RenewAt {
+    fn default() -> Self {
+        RenewAt::Fraction(0.5)
+    }
+}
+
+impl fmt::Display for RenewAt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenewAt::Fraction(fraction) => write!(f, "{}", fraction),
+            RenewAt::Margin(margin) => write!(f, "{}s", margin.as_secs()),
+        }
+    }
+}
+
+impl FromStr for RenewAt {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(secs) = s.strip_suffix('s') {
+            return match secs.parse() {
+                Ok(secs) if secs > 0 => Ok(RenewAt::Margin(Duration::from_secs(secs))),
+                _ => bail!(
+                    "Invalid renewal margin {:?}, expected a positive number of seconds",
+                    s
+                ),
+            };
+        }
+        match parse_fraction(s) {
+            Ok(fraction) if fraction > 0.0 => Ok(RenewAt::Fraction(fraction)),
+            _ => bail!(
+                "Invalid renewal point {:?}, expected a fraction of the lifetime such as 0.5 or 50%, or a margin before expiry such as 30s",
+                s
+            ),
+        }
+    }
+}
+
+// Function to parse a fraction in [0, 1), given as a number such as 0.1 or a percentage
+// such as 10%.
+pub fn parse_fraction(s: &str) -> Result<f64> {
+    let fraction = match s.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().map(|p| p / 100.0),
+        None => s.parse(),
+    };
+    match fraction {
+        Ok(fraction) if (0.0..1.0).contains(&fraction) => Ok(fraction),
+        _ => bail!(
+            "Invalid fraction {:?}, expected a number from 0 to 1 or a percentage",
+            s
+        ),
+    }
+}
+
+// How mappings are scheduled for renewal. A jitter moves every renewal earlier by a random
+// part of its delay, up to that fraction, so that clients do not renew in lockstep.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Renewal {
+    pub at: RenewAt,
+    pub jitter: f64,
+}
+
+impl Renewal {
+    // Get the delay before renewing a mapping granted for `lifetime`, along with whether
+    // the margin did not fit in that lifetime, in which case the mapping is renewed at half
+    // of it instead.
+    pub fn delay(&self, lifetime: Duration) -> (Duration, bool) {
+        let (delay, fallback) = match self.at {
+            RenewAt::Fraction(fraction) => (lifetime.mul_f64(fraction), false),
+            RenewAt::Margin(margin) if margin < lifetime => (lifetime - margin, false),
+            RenewAt::Margin(_) => (lifetime / 2, true),
+        };
+        (
+            delay - delay.mul_f64(self.jitter * fastrand::f64()),
+            fallback,
+        )
+    }
+}
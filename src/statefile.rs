@@ -0,0 +1,183 @@
+use crate::backend::Session;
+use crate::codec::Protocol;
+use crate::mapping::Mapping;
+use crate::output::{replace, timestamp};
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+// A port granted to a mapping, saved so that the next run can ask for it again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedPort {
+    pub name: String,
+    pub protocol: SavedProtocol,
+    pub private_port: u16,
+    pub public_port: u16,
+    pub gateway: Ipv4Addr,
+    // Nonce of the PCP mapping in hexadecimal, which its renewals and deletion must repeat.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    pub saved_at: String,
+}
+
+impl SavedPort {
+    // What the gateway granted, regardless of when.
+    fn grant(&self) -> (SavedProtocol, u16, u16, Ipv4Addr, Option<&str>) {
+        (
+            self.protocol,
+            self.private_port,
+            self.public_port,
+            self.gateway,
+            self.nonce.as_deref(),
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SavedProtocol {
+    Tcp,
+    Udp,
+}
+
+impl From<SavedProtocol> for Protocol {
+    fn from(protocol: SavedProtocol) -> Protocol {
+        match protocol {
+            SavedProtocol::Tcp => Protocol::TCP,
+            SavedProtocol::Udp => Protocol::UDP,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Content {
+    ports: Vec<SavedPort>,
+}
+
+// The state file, which keeps the last ports of every mapping across restarts.
+pub struct StateFile {
+    path: PathBuf,
+    ports: Vec<SavedPort>,
+}
+
+impl StateFile {
+    // Read the ports saved by the previous run. The file is only a hint: when it is missing
+    // or unreadable, mappings get fresh ports.
+    pub fn load(path: PathBuf) -> StateFile {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                warn!("Failed to read state file {:?}: {}", path, e);
+                String::new()
+            }
+        };
+        let ports = if content.is_empty() {
+            vec![]
+        } else {
+            match serde_json::from_str::<Content>(&content) {
+                Ok(content) => content.ports,
+                Err(e) => {
+                    warn!("Ignoring invalid state file {:?}: {}", path, e);
+                    vec![]
+                }
+            }
+        };
+        StateFile { path, ports }
+    }
+
+    // Ports saved for a mapping on the given gateway, as (protocol, private, public).
+    pub fn ports(&self, name: &str, gateway: Ipv4Addr) -> Vec<(Protocol, u16, u16)> {
+        self.ports
+            .iter()
+            .filter(|p| p.name == name && p.gateway == gateway)
+            .map(|p| (p.protocol.into(), p.private_port, p.public_port))
+            .collect()
+    }
+
+    // Hand the nonces saved for the mappings on the given gateway to the session, so that
+    // the mappings of the previous run can be renewed or deleted, and tell it that the
+    // gateway may still hold the others.
+    pub fn restore_nonces(&self, gateway: Ipv4Addr, session: &Session) {
+        for p in self.ports.iter().filter(|p| p.gateway == gateway) {
+            let protocol = p.protocol.into();
+            session.set_held(protocol, p.private_port);
+            let Some(nonce) = p.nonce.as_deref() else {
+                continue;
+            };
+            if session.nonce(protocol, p.private_port).is_some() {
+                continue;
+            }
+            match parse_nonce(nonce) {
+                Some(nonce) => session.set_nonce(protocol, p.private_port, nonce),
+                None => warn!("Ignoring invalid nonce {:?} in state file {:?}", nonce, self.path),
+            }
+        }
+    }
+
+    // Record the ports granted to a mapping, writing the file when they changed. Renewals on
+    // the same ports do not touch the disk.
+    pub fn save(&mut self, m: &Mapping, gateway: Ipv4Addr, session: &Session) -> Result<()> {
+        let saved_at = timestamp(SystemTime::now())?;
+        let mut ports = vec![];
+        for (protocol, saved) in [
+            (Protocol::TCP, SavedProtocol::Tcp),
+            (Protocol::UDP, SavedProtocol::Udp),
+        ] {
+            if let Some(mr) = m.get(protocol) {
+                ports.push(SavedPort {
+                    name: m.spec.name.clone(),
+                    protocol: saved,
+                    private_port: mr.private_port,
+                    public_port: mr.public_port,
+                    gateway,
+                    nonce: session
+                        .nonce(protocol, mr.private_port)
+                        .map(|nonce| nonce.iter().map(|b| format!("{:02x}", b)).collect()),
+                    saved_at: saved_at.clone(),
+                });
+            }
+        }
+        let previous = self.ports.iter().filter(|p| p.name == m.spec.name);
+        if previous
+            .map(SavedPort::grant)
+            .eq(ports.iter().map(SavedPort::grant))
+        {
+            return Ok(());
+        }
+        self.ports.retain(|p| p.name != m.spec.name);
+        self.ports.extend(ports);
+        self.write()
+    }
+
+    // Forget the mappings which are not declared anymore.
+    pub fn retain(&mut self, names: &[&str]) {
+        self.ports.retain(|p| names.contains(&p.name.as_str()));
+    }
+
+    fn write(&self) -> Result<()> {
+        let content = Content {
+            ports: self.ports.clone(),
+        };
+        let json = serde_json::to_string_pretty(&content)?;
+        replace(&self.path, &(json + "\n"))
+            .with_context(|| format!("Failed to write state file {:?}", self.path))
+    }
+}
+
+// Function to parse a nonce saved as 24 hexadecimal digits.
+fn parse_nonce(hex: &str) -> Option<[u8; 12]> {
+    if hex.len() != 24 || !hex.is_ascii() {
+        return None;
+    }
+    let mut nonce = [0; 12];
+    for (i, byte) in nonce.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(nonce)
+}
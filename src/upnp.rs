@@ -0,0 +1,590 @@
+use crate::backend::{Backend, BoxFuture, GatewayError, Grant, PublicAddress, Session};
+use crate::codec::Protocol;
+use crate::netns::{self, NetnsSpec};
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info};
+use socket2::{Domain, SockRef, Socket, Type};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::{task, time};
+
+// Group and port of SSDP searches (UPnP Device Architecture 1.1, 1.3.2).
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+// Timeouts of the successive searches, devices answering within MX seconds.
+const SEARCH_TIMEOUTS: [u64; 2] = [1000, 2000];
+
+// Services which can map ports, the first ones being preferred.
+const SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+// Time given to the gateway to answer an HTTP request.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Description of the mappings, shown in the user interface of the gateway.
+const DESCRIPTION: &str = "natpmp-setup";
+
+// Ports picked for mappings when neither the public nor the internal port is given, or when
+// the one asked for is taken.
+const DYNAMIC_PORTS: RangeInclusive<u16> = 49152..=65535;
+// Ports tried for a mapping before giving up, when those asked for are taken.
+const MAX_PORT_ATTEMPTS: usize = 8;
+// Entries of the gateway read to find the mappings to delete.
+const MAX_ENTRIES: usize = 1024;
+
+// Error codes of the WANIPConnection service (WANIPConnection:2 2.5).
+const INVALID_ACTION: u16 = 401;
+const OPTIONAL_ACTION_NOT_IMPLEMENTED: u16 = 602;
+const ACTION_NOT_AUTHORIZED: u16 = 606;
+const SPECIFIED_ARRAY_INDEX_INVALID: u16 = 713;
+const NO_SUCH_ENTRY_IN_ARRAY: u16 = 714;
+const CONFLICT_IN_MAPPING_ENTRY: u16 = 718;
+const SAME_PORT_VALUES_REQUIRED: u16 = 724;
+const ONLY_PERMANENT_LEASES_SUPPORTED: u16 = 725;
+const NO_PORT_MAPS_AVAILABLE: u16 = 728;
+const CONFLICT_WITH_OTHER_MECHANISMS: u16 = 729;
+
+// UPnP Internet Gateway Device client, asking the WAN connection service of the gateway for
+// mappings with SOAP actions. Unlike NAT-PMP and PCP, requests run over TCP, so a request
+// which fails is only sent again by the renewals.
+pub struct Igd {
+    gateway: Ipv4Addr,
+    netns: Option<NetnsSpec>,
+    // Address of this host on the network of the gateway, which mappings forward to.
+    client_ip: Ipv4Addr,
+    // Control endpoint and type of the service.
+    control: SocketAddrV4,
+    control_path: String,
+    service_type: &'static str,
+    // UPnP has no epoch, so the time since the session started is reported, which never
+    // tells the forwarder that the gateway lost its mappings: the renewals add them again
+    // anyway.
+    session: Session,
+}
+
+// An error returned by the service in a SOAP fault.
+#[derive(Debug)]
+struct Fault {
+    code: u16,
+    description: String,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Gateway returned UPnP error {} ({})",
+            self.code, self.description
+        )
+    }
+}
+
+impl std::error::Error for Fault {}
+
+impl Igd {
+    // Find the Internet Gateway Device of the gateway with SSDP and read its description,
+    // returning `None` if the gateway does not answer the search.
+    pub async fn discover(
+        gateway: Ipv4Addr,
+        netns: Option<&NetnsSpec>,
+        session: &Session,
+    ) -> Result<Option<Igd>> {
+        let Some((location, client_ip)) = search(gateway, netns).await? else {
+            info!("Gateway {} does not answer UPnP searches", gateway);
+            return Ok(None);
+        };
+        debug!("Gateway {} describes itself at {}", gateway, location);
+        let (addr, path) = parse_url(&location)?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, addr
+        );
+        let (status, description) = http(netns, addr, request).await?;
+        if status != 200 {
+            bail!(
+                "Failed to read the UPnP description {}: HTTP status {}",
+                location,
+                status
+            );
+        }
+        let Some((service_type, control_url)) = find_service(&description) else {
+            bail!("Gateway {} has no UPnP service to map ports", gateway);
+        };
+        let base = element(&description, "URLBase").unwrap_or(&location);
+        let (control, control_path) = resolve_url(base, &control_url)?;
+        info!("Gateway {} has UPnP service {}", gateway, service_type);
+        Ok(Some(Igd {
+            gateway,
+            netns: netns.cloned(),
+            client_ip,
+            control,
+            control_path,
+            service_type,
+            session: session.clone(),
+        }))
+    }
+
+    async fn external_address(&mut self) -> Result<PublicAddress> {
+        let response = self
+            .call("GetExternalIPAddress", &[])
+            .await
+            .map_err(gateway_error)?;
+        let address = element(&response, "NewExternalIPAddress").unwrap_or_default();
+        info!("Got GetExternalIPAddress response: IP: {:?}", address);
+        // The gateway has no address while its WAN connection is down.
+        match address.trim().parse::<Ipv4Addr>() {
+            Ok(ip) if !ip.is_unspecified() => Ok(PublicAddress {
+                ip: Some(ip),
+                epoch: self.epoch(),
+            }),
+            _ => Err(GatewayError::NetworkFailure.into()),
+        }
+    }
+
+    async fn request_port(
+        &mut self,
+        protocol: Protocol,
+        internal: u16,
+        external: u16,
+        lifetime: u32,
+    ) -> Result<Grant> {
+        let name = protocol_name(protocol);
+        if lifetime == 0 {
+            return self.delete_ports(protocol, internal, 0).await;
+        }
+        // Both ports are required: internal port 0 is mapped to the same port as the public
+        // one, and a public port is picked when the gateway may choose it.
+        let mut port = match (external, internal) {
+            (0, 0) => fastrand::u16(DYNAMIC_PORTS),
+            (0, internal) => internal,
+            (external, _) => external,
+        };
+        let mut lease = lifetime;
+        for _ in 0..MAX_PORT_ATTEMPTS {
+            let private = if internal == 0 { port } else { internal };
+            let args = [
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", port.to_string()),
+                ("NewProtocol", name.to_owned()),
+                ("NewInternalPort", private.to_string()),
+                ("NewInternalClient", self.client_ip.to_string()),
+                ("NewEnabled", "1".to_owned()),
+                ("NewPortMappingDescription", DESCRIPTION.to_owned()),
+                ("NewLeaseDuration", lease.to_string()),
+            ];
+            let e = match self.call("AddPortMapping", &args).await {
+                Ok(_) => {
+                    info!(
+                        "Added {} port mapping: Internal: {}, External: {}, Lease: {}s",
+                        name, private, port, lease
+                    );
+                    // A permanent mapping is renewed as any other, and deleted on exit.
+                    return Ok(Grant {
+                        private_port: private,
+                        public_port: port,
+                        lifetime: Duration::from_secs(lifetime.into()),
+                        epoch: self.epoch(),
+                        public_ip: None,
+                    });
+                }
+                Err(e) => e,
+            };
+            match e.downcast_ref::<Fault>().map(|f| f.code) {
+                Some(ONLY_PERMANENT_LEASES_SUPPORTED) if lease != 0 => {
+                    debug!("Gateway only supports permanent mappings");
+                    lease = 0;
+                }
+                Some(SAME_PORT_VALUES_REQUIRED) if port != private => {
+                    debug!("Gateway requires the same public and internal ports");
+                    port = private;
+                }
+                Some(CONFLICT_IN_MAPPING_ENTRY | CONFLICT_WITH_OTHER_MECHANISMS) => {
+                    // Like a NAT-PMP gateway, grant another port than the one asked for.
+                    debug!("{} port {} is taken, trying another one", name, port);
+                    port = fastrand::u16(DYNAMIC_PORTS);
+                }
+                _ => return Err(gateway_error(e)),
+            }
+        }
+        bail!(
+            "Gateway refused {} mappings on {} ports",
+            name,
+            MAX_PORT_ATTEMPTS
+        );
+    }
+
+    // Delete the mapping of an internal port on its public port, or when it is unknown, the
+    // mappings of this client for the internal port, or for any with internal port 0, as
+    // listed by the gateway. Deleting nothing is an error.
+    async fn delete_ports(
+        &mut self,
+        protocol: Protocol,
+        internal: u16,
+        public: u16,
+    ) -> Result<Grant> {
+        let name = protocol_name(protocol);
+        let ports = match public {
+            0 => self.find_ports(name, internal).await?,
+            public => vec![public],
+        };
+        if ports.is_empty() {
+            bail!(
+                "Gateway has no {} mapping of internal port {} for {}",
+                name,
+                internal,
+                self.client_ip
+            );
+        }
+        for public in ports {
+            let args = [
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", public.to_string()),
+                ("NewProtocol", name.to_owned()),
+            ];
+            match self.call("DeletePortMapping", &args).await {
+                Ok(_) => info!("Deleted {} port mapping: External: {}", name, public),
+                // The gateway already forgot it, as after its lease.
+                Err(e) if fault_code(&e) == Some(NO_SUCH_ENTRY_IN_ARRAY) => {
+                    debug!("No {} port mapping to delete on external port {}", name, public)
+                }
+                Err(e) => return Err(gateway_error(e)),
+            }
+        }
+        Ok(Grant {
+            private_port: internal,
+            public_port: 0,
+            lifetime: Duration::ZERO,
+            epoch: self.epoch(),
+            public_ip: None,
+        })
+    }
+
+    // List the public ports of the mappings this program made for an internal port, or for
+    // any with internal port 0, going through the entries of the gateway. Mappings of other
+    // programs of this host are told apart by their description.
+    async fn find_ports(&self, name: &str, internal: u16) -> Result<Vec<u16>> {
+        let mut ports = vec![];
+        for index in 0..MAX_ENTRIES {
+            let args = [("NewPortMappingIndex", index.to_string())];
+            let entry = match self.call("GetGenericPortMappingEntry", &args).await {
+                Ok(entry) => entry,
+                // The end of the list.
+                Err(e) if fault_code(&e) == Some(SPECIFIED_ARRAY_INDEX_INVALID) => break,
+                Err(e) => return Err(gateway_error(e)),
+            };
+            let field = |name| element(&entry, name).unwrap_or_default().trim();
+            let ours = field("NewProtocol") == name
+                && field("NewInternalClient") == self.client_ip.to_string()
+                && field("NewPortMappingDescription") == DESCRIPTION
+                && (internal == 0 || field("NewInternalPort") == internal.to_string());
+            if let (true, Ok(public)) = (ours, field("NewExternalPort").parse()) {
+                ports.push(public);
+            }
+        }
+        Ok(ports)
+    }
+
+    fn epoch(&self) -> u32 {
+        self.session.epoch()
+    }
+
+    // Invoke an action of the service, returning the body of the response.
+    async fn call(&self, action: &str, args: &[(&str, String)]) -> Result<String> {
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{}>{}</{}>", name, value, name))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{} xmlns:u=\"{}\">{}</u:{}></s:Body></s:Envelope>\r\n",
+            action, self.service_type, args, action
+        );
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\nSOAPAction: \"{}#{}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.control_path,
+            self.control,
+            self.service_type,
+            action,
+            body.len(),
+            body
+        );
+        debug!("{} request sent to {}", action, self.control);
+        let (status, response) = http(self.netns.as_ref(), self.control, request).await?;
+        if status == 200 {
+            return Ok(response);
+        }
+        // Errors come as a SOAP fault with status 500 (UPnP Device Architecture 1.1, 3.2.2).
+        match element(&response, "errorCode").and_then(|c| c.trim().parse().ok()) {
+            Some(code) => Err(Fault {
+                code,
+                description: element(&response, "errorDescription")
+                    .unwrap_or_default()
+                    .to_owned(),
+            }
+            .into()),
+            None => bail!("{} failed with HTTP status {}", action, status),
+        }
+    }
+}
+
+impl Backend for Igd {
+    fn name(&self) -> &'static str {
+        "UPnP"
+    }
+
+    fn gateway(&self) -> Ipv4Addr {
+        self.gateway
+    }
+
+    fn public_address(&mut self) -> BoxFuture<'_, Result<PublicAddress>> {
+        Box::pin(self.external_address())
+    }
+
+    fn map(
+        &mut self,
+        protocol: Protocol,
+        internal: u16,
+        external: u16,
+        lifetime: u32,
+    ) -> BoxFuture<'_, Result<Grant>> {
+        Box::pin(self.request_port(protocol, internal, external, lifetime))
+    }
+
+    fn delete(
+        &mut self,
+        protocol: Protocol,
+        internal: u16,
+        public: u16,
+    ) -> BoxFuture<'_, Result<Grant>> {
+        Box::pin(self.delete_ports(protocol, internal, public))
+    }
+}
+
+// Function to search for the Internet Gateway Device of the gateway, returning the URL of its
+// description along with the address of this host on its network. The search is multicast,
+// and also sent to the gateway directly, which UPnP 1.1 devices answer.
+async fn search(
+    gateway: Ipv4Addr,
+    netns: Option<&NetnsSpec>,
+) -> Result<Option<(String, Ipv4Addr)>> {
+    let (socket, client_ip) = netns::enter(netns, || {
+        let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        probe.connect((gateway, SSDP_PORT))?;
+        let client_ip = match probe.local_addr()? {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        SockRef::from(&socket).set_multicast_if_v4(&client_ip)?;
+        socket.set_multicast_ttl_v4(2)?;
+        socket.set_nonblocking(true)?;
+        Ok((socket, client_ip))
+    })
+    .with_context(|| format!("Failed to search for UPnP gateway {}", gateway))?;
+    let socket = UdpSocket::from_std(socket)?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}:{}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+        SSDP_GROUP, SSDP_PORT, SEARCH_TARGET
+    );
+    let mut buf = [0; 2048];
+    for timeout in SEARCH_TIMEOUTS {
+        for target in [SSDP_GROUP, gateway] {
+            if let Err(e) = socket
+                .send_to(request.as_bytes(), (target, SSDP_PORT))
+                .await
+            {
+                debug!("Failed to send UPnP search to {}: {}", target, e);
+            }
+        }
+        debug!("UPnP search sent! (will timeout in {}ms)", timeout);
+        let deadline = time::Instant::now() + Duration::from_millis(timeout);
+        loop {
+            let (len, from) = match time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+                Err(_) => break,
+                Ok(received) => received.context("Failed to receive UPnP search response")?,
+            };
+            // Other devices of the network may answer the multicast search.
+            if from.ip() != IpAddr::V4(gateway) {
+                debug!("Ignoring UPnP search response from {}", from);
+                continue;
+            }
+            let response = String::from_utf8_lossy(&buf[..len]);
+            match header(&response, "location") {
+                Some(location) if response.starts_with("HTTP/1.1 200") => {
+                    return Ok(Some((location.to_owned(), client_ip)));
+                }
+                _ => debug!("Ignoring invalid UPnP search response: {:?}", response),
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Function to send an HTTP/1.1 request and read the response until the gateway closes the
+// connection, returning its status and body. The socket is created in the network
+// namespace, if any. The exchange blocks a thread of its own, with timeouts on the socket.
+async fn http(
+    netns: Option<&NetnsSpec>,
+    addr: SocketAddrV4,
+    request: String,
+) -> Result<(u16, String)> {
+    let socket = netns::enter(netns, || Ok(Socket::new(Domain::IPV4, Type::STREAM, None)?))?;
+    let exchange = move || {
+        socket.connect_timeout(&SocketAddr::V4(addr).into(), HTTP_TIMEOUT)?;
+        socket.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        socket.set_write_timeout(Some(HTTP_TIMEOUT))?;
+        let mut stream = TcpStream::from(socket);
+        stream.write_all(request.as_bytes())?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+        Ok::<_, io::Error>(response)
+    };
+    let response = task::spawn_blocking(exchange)
+        .await?
+        .with_context(|| format!("HTTP request to {} failed", addr))?;
+    let response = String::from_utf8_lossy(&response);
+    let Some((head, body)) = response.split_once("\r\n\r\n") else {
+        bail!("Invalid HTTP response from {}", addr);
+    };
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| anyhow!("Invalid HTTP status from {}", addr))?;
+    let body = match header(head, "transfer-encoding") {
+        Some(encoding) if encoding.eq_ignore_ascii_case("chunked") => {
+            dechunk(body).ok_or_else(|| anyhow!("Invalid chunked HTTP response from {}", addr))?
+        }
+        _ => body.to_owned(),
+    };
+    Ok((status, body))
+}
+
+// Function to get the value of a header of an HTTP message, by case-insensitive name.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+// Function to decode a body sent with the chunked transfer encoding.
+fn dechunk(mut body: &str) -> Option<String> {
+    let mut decoded = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n")?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.push_str(rest.get(..size)?);
+        body = rest.get(size..)?.strip_prefix("\r\n")?;
+    }
+}
+
+// Function to get the text of the first element with the given name, whatever its
+// namespace prefix.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    loop {
+        let start = rest.find('<')?;
+        rest = &rest[start + 1..];
+        let end = rest.find('>')?;
+        let tag = &rest[..end];
+        let tag_name = tag.split_whitespace().next().unwrap_or_default();
+        let local = tag_name.rsplit(':').next().unwrap_or_default();
+        if local == name && !tag.ends_with('/') {
+            let content = &rest[end + 1..];
+            let close = content.find(&format!("</{}>", tag_name))?;
+            return Some(content[..close].trim());
+        }
+        rest = &rest[end + 1..];
+    }
+}
+
+// Function to find the preferred service which can map ports in a device description,
+// returning its type and control URL.
+fn find_service(description: &str) -> Option<(&'static str, String)> {
+    let services: Vec<_> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|s| Some((element(s, "serviceType")?, element(s, "controlURL")?)))
+        .collect();
+    SERVICE_TYPES.into_iter().find_map(|wanted| {
+        let (_, url) = services.iter().find(|(service, _)| *service == wanted)?;
+        Some((wanted, unescape(url)))
+    })
+}
+
+// Function to resolve the XML entities which URLs may contain.
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Function to split an HTTP URL into the address and path to request. The host must be an
+// IPv4 address, as gateways give.
+fn parse_url(url: &str) -> Result<(SocketAddrV4, String)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("Unsupported UPnP URL {:?}, expected http://", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = match host.split_once(':') {
+        Some((ip, port)) => format!("{}:{}", ip, port).parse(),
+        None => format!("{}:80", host).parse(),
+    };
+    let addr =
+        addr.map_err(|_| anyhow!("Unsupported UPnP URL {:?}, expected an IPv4 host", url))?;
+    Ok((addr, path.to_owned()))
+}
+
+// Function to resolve a URL of a device description against its base.
+fn resolve_url(base: &str, url: &str) -> Result<(SocketAddrV4, String)> {
+    if url.starts_with("http://") {
+        return parse_url(url);
+    }
+    let (addr, _) = parse_url(base)?;
+    let path = if url.starts_with('/') {
+        url.to_owned()
+    } else {
+        format!("/{}", url)
+    };
+    Ok((addr, path))
+}
+
+// Function to get the error of the gateway behind a SOAP fault, as the forwarder recovers
+// from those of NAT-PMP.
+fn gateway_error(e: anyhow::Error) -> anyhow::Error {
+    let error = match fault_code(&e) {
+        Some(INVALID_ACTION | OPTIONAL_ACTION_NOT_IMPLEMENTED) => GatewayError::UnsupportedOpcode,
+        Some(ACTION_NOT_AUTHORIZED) => GatewayError::NotAuthorized,
+        Some(NO_PORT_MAPS_AVAILABLE) => GatewayError::OutOfResources,
+        Some(_) => GatewayError::Undefined,
+        None => return e,
+    };
+    e.context(error)
+}
+
+fn fault_code(e: &anyhow::Error) -> Option<u16> {
+    e.downcast_ref::<Fault>().map(|f| f.code)
+}
+
+fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::TCP => "TCP",
+        Protocol::UDP => "UDP",
+    }
+}
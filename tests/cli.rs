@@ -0,0 +1,375 @@
+mod common;
+
+use common::gateway::{FakeGateway, TCP, UDP};
+use std::fs;
+use std::process::{Output, Stdio};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time;
+
+// Function to build a command running the binary against the given gateway, without
+// inheriting `NATPMP_*` variables from the environment.
+fn natpmp_setup(gw: &FakeGateway) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_natpmp-setup"));
+    cmd.env_clear()
+        .arg("--gateway")
+        .arg(gw.addr().to_string())
+        .kill_on_drop(true);
+    cmd
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[tokio::test]
+async fn prints_the_public_ip() {
+    let gw = FakeGateway::start().await;
+    let output = natpmp_setup(&gw).arg("public-ip").output().await.unwrap();
+    assert_eq!(stdout(&output), "203.0.113.1\n");
+}
+
+#[tokio::test]
+async fn creates_mappings_once() {
+    let gw = FakeGateway::start().await;
+    gw.set_max_lifetime(120);
+    let output = natpmp_setup(&gw)
+        .args(["once", "-m", "web:tcp", "-m", "game:udp:0:60"])
+        .output()
+        .await
+        .unwrap();
+    assert_eq!(
+        stdout(&output),
+        "web: TCP 40000, UDP -, lifetime 120s\ngame: TCP -, UDP 40001, lifetime 60s\n"
+    );
+}
+
+#[tokio::test]
+async fn runs_until_terminated() {
+    let gw = FakeGateway::start().await;
+    let dir = tempfile::tempdir().unwrap();
+    let csv = dir.path().join("natpmp.csv");
+    let mut daemon = natpmp_setup(&gw)
+        .arg("run")
+        .arg("--output")
+        .arg(&csv)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let pid = daemon.id().unwrap();
+
+    // Wait for the daemon to write the output file.
+    time::timeout(Duration::from_secs(10), async {
+        while !csv.exists() {
+            time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Output file was not written");
+    assert_eq!(
+        fs::read_to_string(&csv).unwrap(),
+        format!("default,{},40000,40000,203.0.113.1\n", pid)
+    );
+    let status = natpmp_setup(&gw)
+        .arg("status")
+        .arg("--output")
+        .arg(&csv)
+        .output()
+        .await
+        .unwrap();
+    assert_eq!(
+        stdout(&status),
+        format!(
+            "default: TCP 40000, UDP 40000, public IP 203.0.113.1 (PID {} running)\n",
+            pid
+        )
+    );
+
+    // Mappings are released and the output file removed on SIGTERM.
+    let kill = Command::new("kill").arg(pid.to_string()).status().await.unwrap();
+    assert!(kill.success());
+    let status = time::timeout(Duration::from_secs(10), daemon.wait())
+        .await
+        .expect("Daemon did not exit")
+        .unwrap();
+    assert!(status.success(), "{}", status);
+    assert!(!csv.exists());
+    assert_eq!(gw.mappings(), []);
+}
+
+#[tokio::test]
+async fn rejects_invalid_gateways() {
+    let output = Command::new(env!("CARGO_BIN_EXE_natpmp-setup"))
+        .env_clear()
+        .args(["--gateway", "10.2.0", "public-ip"])
+        .output()
+        .await
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Invalid gateway \"10.2.0\""), "{}", stderr);
+}
+
+#[tokio::test]
+async fn enters_a_network_namespace() {
+    let gw = FakeGateway::start().await;
+    // The namespace of the test itself, which the gateway is reachable from.
+    let netns = format!("/proc/{}/ns/net", std::process::id());
+    let output = natpmp_setup(&gw)
+        .args(["--netns", &netns, "public-ip"])
+        .output()
+        .await
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("CAP_SYS_ADMIN is required") {
+        eprintln!("Skipped, entering a network namespace is not permitted");
+        return;
+    }
+    assert_eq!(stdout(&output), "203.0.113.1\n");
+
+    let output = natpmp_setup(&gw)
+        .args(["--netns", "natpmp-setup-missing", "public-ip"])
+        .output()
+        .await
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Failed to open network namespace /run/netns/natpmp-setup-missing"),
+        "{}",
+        stderr
+    );
+}
+
+#[tokio::test]
+async fn runs_several_tunnels() {
+    let (a, b) = (FakeGateway::start().await, FakeGateway::start().await);
+    b.set_public_ip("198.51.100.7".parse().unwrap());
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("natpmp.toml");
+    let (out_a, out_b) = (dir.path().join("a.csv"), dir.path().join("b.csv"));
+    fs::write(
+        &config,
+        format!(
+            "[[tunnel]]\nname = \"a\"\ngateway = \"{}\"\noutput = {:?}\n\n\
+             [[tunnel]]\nname = \"b\"\ngateway = \"{}\"\noutput = {:?}\n\
+             [[tunnel.mapping]]\nname = \"web\"\nprotocol = \"tcp\"\n",
+            a.addr(),
+            out_a,
+            b.addr(),
+            out_b
+        ),
+    )
+    .unwrap();
+    let natpmp_setup = || {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_natpmp-setup"));
+        cmd.env_clear().arg("--config").arg(&config).kill_on_drop(true);
+        cmd
+    };
+
+    // Commands talking to a single gateway need a tunnel to be selected.
+    let output = natpmp_setup().arg("public-ip").output().await.unwrap();
+    assert!(!output.status.success());
+    let output = natpmp_setup()
+        .args(["--tunnel", "b", "public-ip"])
+        .output()
+        .await
+        .unwrap();
+    assert_eq!(stdout(&output), "198.51.100.7\n");
+    // Flags win over the settings of the selected tunnel, not over those of several.
+    let gateway = a.addr().to_string();
+    let output = natpmp_setup()
+        .args(["--tunnel", "b", "--gateway", &gateway, "public-ip"])
+        .output()
+        .await
+        .unwrap();
+    assert_eq!(stdout(&output), "203.0.113.1\n");
+    let output = natpmp_setup()
+        .args(["--gateway", &gateway, "run"])
+        .output()
+        .await
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--gateway would replace the settings of tunnel a"), "{}", stderr);
+
+    let mut daemon = natpmp_setup()
+        .arg("run")
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let pid = daemon.id().unwrap();
+    time::timeout(Duration::from_secs(10), async {
+        while !out_a.exists() || !out_b.exists() {
+            time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Output files were not written");
+    assert_eq!(a.mappings(), [(UDP, 40000), (TCP, 40000)]);
+    assert_eq!(b.mappings(), [(TCP, 40000)]);
+    let status = natpmp_setup().arg("status").output().await.unwrap();
+    assert_eq!(
+        stdout(&status),
+        format!(
+            "a/default: TCP 40000, UDP 40000, public IP 203.0.113.1 (PID {0} running)\n\
+             b/web: TCP 40000, UDP -, public IP 198.51.100.7 (PID {0} running)\n",
+            pid
+        )
+    );
+
+    let kill = Command::new("kill").arg(pid.to_string()).status().await.unwrap();
+    assert!(kill.success());
+    let status = time::timeout(Duration::from_secs(10), daemon.wait())
+        .await
+        .expect("Daemon did not exit")
+        .unwrap();
+    assert!(status.success(), "{}", status);
+    assert!(!out_a.exists() && !out_b.exists());
+    assert_eq!(a.mappings(), []);
+    assert_eq!(b.mappings(), []);
+}
+
+#[tokio::test]
+async fn keeps_running_the_other_tunnels() {
+    let (a, b) = (FakeGateway::start().await, FakeGateway::start().await);
+    // The second gateway refuses port forwarding.
+    b.fail_with(2);
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("natpmp.toml");
+    let (out_a, out_b) = (dir.path().join("a.csv"), dir.path().join("b.csv"));
+    fs::write(
+        &config,
+        format!(
+            "[[tunnel]]\nname = \"a\"\ngateway = \"{}\"\noutput = {:?}\n\n\
+             [[tunnel]]\nname = \"b\"\ngateway = \"{}\"\noutput = {:?}\n",
+            a.addr(),
+            out_a,
+            b.addr(),
+            out_b
+        ),
+    )
+    .unwrap();
+    let daemon = Command::new(env!("CARGO_BIN_EXE_natpmp-setup"))
+        .env_clear()
+        .arg("--config")
+        .arg(&config)
+        .arg("run")
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let pid = daemon.id().unwrap();
+    time::timeout(Duration::from_secs(10), async {
+        while !out_a.exists() {
+            time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Output file was not written");
+    assert!(!out_b.exists());
+    assert_eq!(a.mappings(), [(UDP, 40000), (TCP, 40000)]);
+
+    let kill = Command::new("kill").arg(pid.to_string()).status().await.unwrap();
+    assert!(kill.success());
+    let output = time::timeout(Duration::from_secs(10), daemon.wait_with_output())
+        .await
+        .expect("Daemon did not exit")
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Tunnel b: "), "{}", stderr);
+    assert!(stderr.contains("skipping it"), "{}", stderr);
+    assert_eq!(a.mappings(), []);
+}
+
+#[tokio::test]
+async fn starts_the_tunnels_together() {
+    let (a, b) = (FakeGateway::start().await, FakeGateway::start().await);
+    // The second gateway does not answer, so its tunnel takes minutes to start.
+    b.drop_next(usize::MAX);
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("natpmp.toml");
+    let (out_a, out_b) = (dir.path().join("a.csv"), dir.path().join("b.csv"));
+    fs::write(
+        &config,
+        format!(
+            "[[tunnel]]\nname = \"a\"\ngateway = \"{}\"\noutput = {:?}\n\n\
+             [[tunnel]]\nname = \"b\"\ngateway = \"{}\"\noutput = {:?}\n",
+            b.addr(),
+            out_b,
+            a.addr(),
+            out_a
+        ),
+    )
+    .unwrap();
+    let daemon = Command::new(env!("CARGO_BIN_EXE_natpmp-setup"))
+        .env_clear()
+        .arg("--config")
+        .arg(&config)
+        .arg("run")
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let pid = daemon.id().unwrap();
+    time::timeout(Duration::from_secs(10), async {
+        while !out_a.exists() {
+            time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Output file was not written");
+    assert_eq!(a.mappings(), [(UDP, 40000), (TCP, 40000)]);
+
+    // The daemon stops while the second tunnel is still starting.
+    let kill = Command::new("kill").arg(pid.to_string()).status().await.unwrap();
+    assert!(kill.success());
+    let output = time::timeout(Duration::from_secs(10), daemon.wait_with_output())
+        .await
+        .expect("Daemon did not exit")
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!out_a.exists());
+    assert!(!out_b.exists());
+    assert_eq!(a.mappings(), []);
+}
+
+#[tokio::test]
+async fn releases_pcp_mappings_with_the_saved_nonce() {
+    let gw = FakeGateway::start().await;
+    gw.speak_pcp();
+    let dir = tempfile::tempdir().unwrap();
+    let (csv, state) = (dir.path().join("natpmp.csv"), dir.path().join("state.json"));
+    let _daemon = natpmp_setup(&gw)
+        .args(["--backend", "pcp", "run", "-m", "web:tcp:8080"])
+        .arg("--output")
+        .arg(&csv)
+        .arg("--state-file")
+        .arg(&state)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    time::timeout(Duration::from_secs(10), async {
+        while !csv.exists() {
+            time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Output file was not written");
+    assert_eq!(gw.mappings(), [(TCP, 40000)]);
+
+    // The gateway only deletes the mapping with the nonce of the daemon.
+    let release = || {
+        let mut cmd = natpmp_setup(&gw);
+        cmd.args(["--backend", "pcp", "release", "-p", "tcp", "-i", "8080"]);
+        cmd
+    };
+    let output = release().output().await.unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("NOTAUTHORIZED"), "{}", stderr);
+    let output = release().arg("--state-file").arg(&state).output().await.unwrap();
+    assert_eq!(stdout(&output), "Released TCP internal port 8080\n");
+    assert_eq!(gw.mappings(), []);
+}
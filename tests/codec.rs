@@ -0,0 +1,229 @@
+use natpmp_setup::codec::{Body, DecodeError, Opcode, Protocol, Request, Response};
+use std::net::Ipv4Addr;
+
+// Iterations of every fuzz test, with a fixed seed so that failures can be replayed.
+const ITERATIONS: usize = 200_000;
+const SEED: u64 = 0x6e61_7470_6d70;
+
+#[test]
+fn encodes_requests_as_rfc_6886() {
+    assert_eq!(Request::PublicAddress.encode(), [0, 0]);
+    let request = Request::Map {
+        protocol: Protocol::TCP,
+        internal: 8080,
+        external: 40000,
+        lifetime: 7200,
+    };
+    assert_eq!(
+        request.encode(),
+        [0, 2, 0, 0, 0x1f, 0x90, 0x9c, 0x40, 0, 0, 0x1c, 0x20]
+    );
+    assert_eq!(Request::decode(&request.encode()), Ok(request));
+}
+
+#[test]
+fn decodes_responses_as_rfc_6886() {
+    let packet = [0, 128, 0, 0, 0, 0, 3, 232, 203, 0, 113, 1];
+    assert_eq!(
+        Response::decode(&packet),
+        Ok(Response {
+            opcode: Opcode::PublicAddress,
+            result: 0,
+            epoch: 1000,
+            body: Body::PublicAddress(Ipv4Addr::new(203, 0, 113, 1)),
+        })
+    );
+    let packet = [
+        0, 129, 0, 0, 0, 0, 3, 232, 0x1f, 0x90, 0x9c, 0x40, 0, 0, 0, 60,
+    ];
+    assert_eq!(
+        Response::decode(&packet),
+        Ok(Response {
+            opcode: Opcode::Map(Protocol::UDP),
+            result: 0,
+            epoch: 1000,
+            body: Body::Map {
+                internal: 8080,
+                external: 40000,
+                lifetime: 60,
+            },
+        })
+    );
+    // Error responses may stop after the epoch.
+    let packet = [0, 130, 0, 2, 0, 0, 3, 232];
+    let response = Response::decode(&packet).unwrap();
+    assert_eq!((response.result, response.body), (2, Body::Empty));
+}
+
+#[test]
+fn rejects_invalid_packets() {
+    let cases: [(&[u8], DecodeError); 7] = [
+        (&[], DecodeError::Truncated),
+        (&[0], DecodeError::Truncated),
+        // A PCP response.
+        (&[2, 128, 0, 0], DecodeError::Version(2)),
+        // A request where a response is expected.
+        (&[0, 0], DecodeError::Direction),
+        (&[0, 131, 0, 0, 0, 0, 0, 0], DecodeError::Opcode(3)),
+        (&[0, 128, 0, 0], DecodeError::Length { opcode: 0, len: 4 }),
+        // Successful responses must carry every field.
+        (
+            &[0, 128, 0, 0, 0, 0, 0, 1],
+            DecodeError::Length { opcode: 0, len: 8 },
+        ),
+    ];
+    for (packet, error) in cases {
+        assert_eq!(Response::decode(packet), Err(error), "{:?}", packet);
+    }
+    let mut packet = [0, 130, 0, 0, 0, 0, 0, 1, 0, 80, 0, 80, 0, 0, 0, 60].to_vec();
+    packet.push(0);
+    assert_eq!(
+        Response::decode(&packet),
+        Err(DecodeError::Length { opcode: 2, len: 17 })
+    );
+    assert_eq!(
+        Request::decode(&[0, 1, 0, 0]),
+        Err(DecodeError::Length { opcode: 1, len: 4 })
+    );
+    assert_eq!(Request::decode(&[0, 128]), Err(DecodeError::Direction));
+}
+
+#[test]
+fn matches_responses_by_opcode_and_port() {
+    let request = |protocol, internal| Request::Map {
+        protocol,
+        internal,
+        external: 0,
+        lifetime: 60,
+    };
+    let response = |protocol, internal| Response {
+        opcode: Opcode::Map(protocol),
+        result: 0,
+        epoch: 1,
+        body: Body::Map {
+            internal,
+            external: 40000,
+            lifetime: 60,
+        },
+    };
+    assert!(request(Protocol::TCP, 80).matches(&response(Protocol::TCP, 80)));
+    assert!(!request(Protocol::TCP, 80).matches(&response(Protocol::UDP, 80)));
+    assert!(!request(Protocol::TCP, 80).matches(&response(Protocol::TCP, 81)));
+    // Internal port 0 may be mapped to any port.
+    assert!(request(Protocol::TCP, 0).matches(&response(Protocol::TCP, 40000)));
+    assert!(!Request::PublicAddress.matches(&response(Protocol::TCP, 80)));
+    let failure = Response {
+        body: Body::Empty,
+        result: 3,
+        ..response(Protocol::TCP, 0)
+    };
+    assert!(request(Protocol::TCP, 80).matches(&failure));
+}
+
+// Packets are random bytes, mostly starting like NAT-PMP messages so that the fuzzing
+// reaches past the header checks.
+fn random_packet(rng: &mut fastrand::Rng) -> Vec<u8> {
+    let len = match rng.u8(..10) {
+        0 => rng.usize(..1100),
+        _ => rng.usize(..20),
+    };
+    let mut packet: Vec<u8> = (0..len).map(|_| rng.u8(..)).collect();
+    if len >= 2 && rng.bool() {
+        packet[0] = 0;
+        packet[1] = rng.u8(..4) | if rng.bool() { 0x80 } else { 0 };
+    }
+    if len >= 4 && rng.bool() {
+        packet[2] = 0;
+        packet[3] = rng.u8(..7);
+    }
+    packet
+}
+
+#[test]
+fn fuzz_decoding_only_accepts_canonical_packets() {
+    let mut rng = fastrand::Rng::with_seed(SEED);
+    let (mut requests, mut responses) = (0, 0);
+    for _ in 0..ITERATIONS {
+        let packet = random_packet(&mut rng);
+        if let Ok(response) = Response::decode(&packet) {
+            assert_eq!(response.encode(), packet);
+            responses += 1;
+        }
+        if let Ok(request) = Request::decode(&packet) {
+            // The reserved field is ignored, and sent as zeros.
+            let mut expected = packet.clone();
+            if expected.len() > 2 {
+                expected[2..4].fill(0);
+            }
+            assert_eq!(request.encode(), expected);
+            requests += 1;
+        }
+    }
+    // The fuzzing must not only exercise the header checks.
+    assert!(
+        requests > 1000 && responses > 1000,
+        "{} {}",
+        requests,
+        responses
+    );
+}
+
+#[test]
+fn fuzz_round_trips_messages() {
+    let mut rng = fastrand::Rng::with_seed(SEED);
+    let protocol = |rng: &mut fastrand::Rng| {
+        if rng.bool() {
+            Protocol::TCP
+        } else {
+            Protocol::UDP
+        }
+    };
+    for _ in 0..ITERATIONS {
+        let request = match rng.bool() {
+            true => Request::PublicAddress,
+            false => Request::Map {
+                protocol: protocol(&mut rng),
+                internal: rng.u16(..),
+                external: rng.u16(..),
+                lifetime: rng.u32(..),
+            },
+        };
+        assert_eq!(Request::decode(&request.encode()), Ok(request));
+
+        let result = if rng.bool() { 0 } else { rng.u16(..) };
+        let (opcode, body) = match rng.u8(..3) {
+            0 => (
+                Opcode::PublicAddress,
+                Body::PublicAddress(Ipv4Addr::from(rng.u32(..))),
+            ),
+            1 => (
+                Opcode::Map(protocol(&mut rng)),
+                Body::Map {
+                    internal: rng.u16(..),
+                    external: rng.u16(..),
+                    lifetime: rng.u32(..),
+                },
+            ),
+            _ if result != 0 => (Opcode::Map(protocol(&mut rng)), Body::Empty),
+            _ => (
+                Opcode::PublicAddress,
+                Body::PublicAddress(Ipv4Addr::UNSPECIFIED),
+            ),
+        };
+        let response = Response {
+            opcode,
+            result,
+            epoch: rng.u32(..),
+            body,
+        };
+        let packet = response.encode();
+        assert_eq!(Response::decode(&packet), Ok(response));
+        // Any other length is refused, but for error responses stopping after the epoch.
+        let len = rng.usize(..=packet.len() + 4);
+        if len != packet.len() && !(len == 8 && result != 0) {
+            let mut resized = packet.clone();
+            resized.resize(len, 0);
+            assert!(Response::decode(&resized).is_err(), "{:?}", resized);
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use super::next_addr;
+use socket2::SockRef;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+pub const UDP: u8 = 1;
+pub const TCP: u8 = 2;
+
+// Port on which NAT-PMP gateways listen (RFC 6886 3.1).
+const NATPMP_PORT: u16 = 5351;
+// Group and port of public address announcements (RFC 6886 3.2.1).
+const ANNOUNCE_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+const ANNOUNCE_PORT: u16 = 5350;
+
+// A NAT-PMP gateway speaking the RFC 6886 wire format on 127.0.0.N:5351, and optionally
+// PCP (RFC 6887), whose behaviour tests can script while it runs.
+pub struct FakeGateway {
+    addr: Ipv4Addr,
+    socket: Arc<UdpSocket>,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+// A request received by the fake gateway, opcode 0 being a public address request. PCP
+// requests have version 2, MAP requests being recorded with the opcode of their protocol
+// and ANNOUNCE ones with opcode 0.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub version: u8,
+    pub opcode: u8,
+    pub internal: u16,
+    pub external: u16,
+    pub lifetime: u32,
+}
+
+struct State {
+    // Whether PCP requests are answered, instead of being refused as by a NAT-PMP gateway.
+    pcp: bool,
+    public_ip: Ipv4Addr,
+    epoch_start: Instant,
+    epoch_offset: u32,
+    max_lifetime: u32,
+    // Number of requests to ignore.
+    drop: usize,
+    delay: Duration,
+    result: u16,
+    // PCP result code of MAP requests, ANNOUNCE ones still succeeding.
+    map_result: u8,
+    next_port: u16,
+    // Public port and expiry of every (opcode, internal port).
+    mappings: HashMap<(u8, u16), (u16, Instant)>,
+    // Nonce of every PCP mapping, as (opcode, internal port).
+    nonces: HashMap<(u8, u16), [u8; 12]>,
+    // Public ports used by other clients, as (opcode, port).
+    taken: Vec<(u8, u16)>,
+    requests: Vec<Request>,
+}
+
+impl FakeGateway {
+    pub async fn start() -> FakeGateway {
+        let socket = loop {
+            let addr = next_addr();
+            match UdpSocket::bind((addr, NATPMP_PORT)).await {
+                Ok(socket) => break socket,
+                Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+                Err(e) => panic!("Failed to bind fake gateway on {}: {}", addr, e),
+            }
+        };
+        let addr = match socket.local_addr().unwrap() {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            pcp: false,
+            public_ip: Ipv4Addr::new(203, 0, 113, 1),
+            epoch_start: Instant::now(),
+            // Start late enough that an epoch reset is noticeable.
+            epoch_offset: 1000,
+            max_lifetime: 3600,
+            drop: 0,
+            delay: Duration::ZERO,
+            result: 0,
+            map_result: 0,
+            next_port: 40000,
+            mappings: HashMap::new(),
+            nonces: HashMap::new(),
+            taken: vec![],
+            requests: vec![],
+        }));
+        // Announcements leave from the gateway address, like those of a real gateway.
+        SockRef::from(&socket).set_multicast_if_v4(&addr).unwrap();
+        let socket = Arc::new(socket);
+        let task = tokio::spawn(serve(socket.clone(), state.clone()));
+        FakeGateway {
+            addr,
+            socket,
+            state,
+            task,
+        }
+    }
+
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    // Answer PCP requests too, like a gateway speaking both protocols.
+    pub fn speak_pcp(&self) {
+        self.state.lock().unwrap().pcp = true;
+    }
+
+    // Ignore the next `n` requests.
+    pub fn drop_next(&self, n: usize) {
+        self.state.lock().unwrap().drop = n;
+    }
+
+    // Wait before sending every reply.
+    pub fn delay_replies(&self, delay: Duration) {
+        self.state.lock().unwrap().delay = delay;
+    }
+
+    // Answer every request with the given result code, 0 going back to normal.
+    pub fn fail_with(&self, result: u16) {
+        self.state.lock().unwrap().result = result;
+    }
+
+    // Answer PCP MAP requests with the given result code, as a gateway which speaks PCP but
+    // does not forward ports.
+    pub fn refuse_mappings(&self, result: u8) {
+        self.state.lock().unwrap().map_result = result;
+    }
+
+    // Cap the lifetime granted to mappings.
+    pub fn set_max_lifetime(&self, lifetime: u32) {
+        self.state.lock().unwrap().max_lifetime = lifetime;
+    }
+
+    pub fn set_public_ip(&self, public_ip: Ipv4Addr) {
+        self.state.lock().unwrap().public_ip = public_ip;
+    }
+
+    // Multicast the current public address, as a gateway does when it changes. PCP
+    // gateways only announce their epoch.
+    pub async fn announce(&self) {
+        let packet = {
+            let state = self.state.lock().unwrap();
+            if state.pcp {
+                let mut packet = vec![2, 128, 0, 0, 0, 0, 0, 0];
+                packet.extend(state.epoch().to_be_bytes());
+                packet.extend([0; 12]);
+                packet
+            } else {
+                let mut packet = vec![0, 128, 0, 0];
+                packet.extend(state.epoch().to_be_bytes());
+                packet.extend(state.public_ip.octets());
+                packet
+            }
+        };
+        self.socket
+            .send_to(&packet, (ANNOUNCE_GROUP, ANNOUNCE_PORT))
+            .await
+            .unwrap();
+    }
+
+    // Move every existing mapping to another public port, its previous one being taken
+    // over by another client.
+    pub fn change_ports(&self) {
+        let mut state = self.state.lock().unwrap();
+        let State { mappings, taken, .. } = &mut *state;
+        for ((opcode, _), (public, _)) in mappings.iter_mut() {
+            taken.push((*opcode, *public));
+            *public += 1000;
+        }
+    }
+
+    // Give a public port to another client.
+    pub fn take(&self, opcode: u8, port: u16) {
+        self.state.lock().unwrap().taken.push((opcode, port));
+    }
+
+    // Restart the epoch from zero and forget every mapping, as after a reboot.
+    pub fn reset_epoch(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch_start = Instant::now();
+        state.epoch_offset = 0;
+        state.mappings.clear();
+        state.nonces.clear();
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    // Nonces of the PCP requests received, as (opcode, internal port, nonce).
+    pub fn nonces(&self) -> Vec<(u8, u16, [u8; 12])> {
+        let state = self.state.lock().unwrap();
+        state.nonces.iter().map(|(&(opcode, internal), &nonce)| (opcode, internal, nonce)).collect()
+    }
+
+    // Public ports of the live mappings, as (opcode, port) sorted pairs.
+    pub fn mappings(&self) -> Vec<(u8, u16)> {
+        let mut state = self.state.lock().unwrap();
+        state.expire();
+        let mut mappings: Vec<_> = state
+            .mappings
+            .iter()
+            .map(|((opcode, _), (public, _))| (*opcode, *public))
+            .collect();
+        mappings.sort();
+        mappings
+    }
+}
+
+impl Drop for FakeGateway {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(socket: Arc<UdpSocket>, state: Arc<Mutex<State>>) {
+    let mut buf = [0; 1100];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let (reply, delay) = {
+            let mut state = state.lock().unwrap();
+            (state.handle(&buf[..len]), state.delay)
+        };
+        let Some(reply) = reply else {
+            continue;
+        };
+        if delay.is_zero() {
+            let _ = socket.send_to(&reply, from).await;
+        } else {
+            // Later requests are still answered while this reply is delayed.
+            let socket = socket.clone();
+            tokio::spawn(async move {
+                time::sleep(delay).await;
+                let _ = socket.send_to(&reply, from).await;
+            });
+        }
+    }
+}
+
+impl State {
+    fn epoch(&self) -> u32 {
+        self.epoch_offset + self.epoch_start.elapsed().as_secs() as u32
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.mappings.retain(|_, (_, expires)| *expires > now);
+    }
+
+    // Build the reply to a request, if it is answered.
+    fn handle(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        let [version, opcode, ..] = *packet else {
+            return None;
+        };
+        if opcode >= 128 {
+            return None;
+        }
+        if version == 2 && self.pcp {
+            return self.handle_pcp(packet);
+        }
+        let mut request = Request {
+            version,
+            opcode,
+            internal: 0,
+            external: 0,
+            lifetime: 0,
+        };
+        if opcode != 0 && packet.len() >= 12 {
+            request.internal = u16::from_be_bytes([packet[4], packet[5]]);
+            request.external = u16::from_be_bytes([packet[6], packet[7]]);
+            request.lifetime = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        }
+        self.requests.push(request);
+        if self.drop > 0 {
+            self.drop -= 1;
+            return None;
+        }
+
+        let result = match (version, opcode) {
+            (0, 0..=2) => self.result,
+            (0, _) => 5,
+            _ => 1,
+        };
+        let mut reply = vec![0, 128 + opcode];
+        reply.extend(result.to_be_bytes());
+        reply.extend(self.epoch().to_be_bytes());
+        if opcode == 0 {
+            reply.extend(self.public_ip.octets());
+        } else if result != 0 || opcode > 2 {
+            // External port and lifetime are zero in error responses.
+            reply.extend(request.internal.to_be_bytes());
+            reply.extend([0; 6]);
+        } else {
+            let (internal, public, lifetime) = self.map(request);
+            reply.extend(internal.to_be_bytes());
+            reply.extend(public.to_be_bytes());
+            reply.extend(lifetime.to_be_bytes());
+        }
+        Some(reply)
+    }
+
+    // Build the reply to a PCP request (RFC 6887 7.2), if it is answered.
+    fn handle_pcp(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.len() < 24 {
+            return None;
+        }
+        let opcode = packet[1];
+        let mut request = Request {
+            version: 2,
+            opcode: 0,
+            internal: 0,
+            external: 0,
+            lifetime: u32::from_be_bytes(packet[4..8].try_into().unwrap()),
+        };
+        let map = opcode == 1 && packet.len() >= 60;
+        let nonce: [u8; 12] = packet.get(24..36).map_or([0; 12], |n| n.try_into().unwrap());
+        let protocol = packet.get(36).copied().unwrap_or_default();
+        if map {
+            request.opcode = match protocol {
+                6 => TCP,
+                17 => UDP,
+                _ => 0,
+            };
+            request.internal = u16::from_be_bytes([packet[40], packet[41]]);
+            request.external = u16::from_be_bytes([packet[42], packet[43]]);
+        }
+        self.requests.push(request);
+        if self.drop > 0 {
+            self.drop -= 1;
+            return None;
+        }
+
+        let key = (request.opcode, request.internal);
+        let result = match self.result {
+            // The NAT-PMP codes scripted by tests, as their PCP counterparts.
+            0 if opcode == 0 => 0,
+            0 if !map => 4,
+            0 if self.map_result != 0 => self.map_result,
+            0 if request.opcode == 0 => 9,
+            0 if request.internal == 0 && request.lifetime != 0 => 3,
+            0 if self.mappings.contains_key(&key) && self.nonces.get(&key) != Some(&nonce) => 2,
+            0 => 0,
+            3 => 7,
+            4 => 8,
+            5 => 4,
+            result => result as u8,
+        };
+        let (mut internal, mut public, mut lifetime) = (request.internal, 0, 0);
+        if map && result == 0 {
+            (internal, public, lifetime) = self.map(request);
+            if lifetime == 0 {
+                self.nonces.remove(&key);
+            } else {
+                self.nonces.insert((request.opcode, internal), nonce);
+            }
+        }
+        let mut reply = vec![2, 128 + opcode, 0, result];
+        reply.extend(lifetime.to_be_bytes());
+        reply.extend(self.epoch().to_be_bytes());
+        reply.extend([0; 12]);
+        if opcode == 1 {
+            reply.extend(nonce);
+            reply.extend([protocol, 0, 0, 0]);
+            reply.extend(internal.to_be_bytes());
+            reply.extend(public.to_be_bytes());
+            let ip = if result == 0 { self.public_ip } else { Ipv4Addr::UNSPECIFIED };
+            reply.extend(ip.to_ipv6_mapped().octets());
+        }
+        Some(reply)
+    }
+
+    // Create, renew or delete a mapping, returning its internal and public ports and lifetime.
+    fn map(&mut self, request: Request) -> (u16, u16, u32) {
+        let Request {
+            opcode,
+            internal,
+            external,
+            lifetime,
+            ..
+        } = request;
+        self.expire();
+        if lifetime == 0 {
+            // An internal port of 0 deletes every mapping of the protocol (RFC 6886 3.4).
+            if internal == 0 {
+                self.mappings.retain(|(op, _), _| *op != opcode);
+            } else {
+                self.mappings.remove(&(opcode, internal));
+            }
+            return (internal, 0, 0);
+        }
+
+        let lifetime = lifetime.min(self.max_lifetime);
+        let expires = Instant::now() + Duration::from_secs(lifetime.into());
+        if let Some((public, at)) = self.mappings.get_mut(&(opcode, internal)) {
+            *at = expires;
+            return (internal, *public, lifetime);
+        }
+        let public = if external != 0 && !self.taken(opcode, external) {
+            external
+        } else {
+            loop {
+                let port = self.next_port;
+                self.next_port += 1;
+                if !self.taken(opcode, port) {
+                    break port;
+                }
+            }
+        };
+        // Like ProtonVPN, an internal port of 0 is mapped to the same port as the public one.
+        let internal = if internal == 0 { public } else { internal };
+        self.mappings.insert((opcode, internal), (public, expires));
+        (internal, public, lifetime)
+    }
+
+    fn taken(&self, opcode: u8, port: u16) -> bool {
+        self.taken.contains(&(opcode, port))
+            || self
+                .mappings
+                .iter()
+                .any(|((op, _), (public, _))| *op == opcode && *public == port)
+    }
+}
@@ -0,0 +1,329 @@
+use super::next_addr;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+
+// Port on which devices answer SSDP searches (UPnP Device Architecture 1.1, 1.3.2).
+const SSDP_PORT: u16 = 1900;
+const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+const CONTROL_PATH: &str = "/ctl/IPConn";
+
+// A UPnP Internet Gateway Device answering SSDP searches on 127.0.0.N:1900, with its
+// description and WANIPConnection control served over HTTP, whose behaviour tests can
+// script while it runs. It does not speak NAT-PMP, so its NAT-PMP port is closed.
+pub struct FakeIgd {
+    addr: Ipv4Addr,
+    state: Arc<Mutex<State>>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+// An action invoked on the fake device, with its arguments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Action {
+    pub name: String,
+    pub args: HashMap<String, String>,
+}
+
+impl Action {
+    pub fn arg(&self, name: &str) -> &str {
+        self.args.get(name).map_or("", String::as_str)
+    }
+}
+
+struct State {
+    public_ip: Ipv4Addr,
+    // Whether only mappings with a lease duration of 0 are accepted.
+    permanent_only: bool,
+    // UPnP error code to answer every action with, 0 for none.
+    error: u16,
+    // Internal port, lease duration, internal client and description of every (protocol,
+    // public port).
+    mappings: HashMap<(String, u16), (u16, u32, String, String)>,
+    // Public ports used by other clients, as (protocol, port).
+    taken: Vec<(String, u16)>,
+    actions: Vec<Action>,
+}
+
+impl FakeIgd {
+    pub async fn start() -> FakeIgd {
+        let ssdp = loop {
+            let addr = next_addr();
+            match UdpSocket::bind((addr, SSDP_PORT)).await {
+                Ok(socket) => break socket,
+                Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+                Err(e) => panic!("Failed to bind fake IGD on {}: {}", addr, e),
+            }
+        };
+        let addr = match ssdp.local_addr().unwrap() {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let http = TcpListener::bind((addr, 0)).await.unwrap();
+        let location = format!("http://{}/rootDesc.xml", http.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State {
+            public_ip: Ipv4Addr::new(203, 0, 113, 1),
+            permanent_only: false,
+            error: 0,
+            mappings: HashMap::new(),
+            taken: vec![],
+            actions: vec![],
+        }));
+        let tasks = [
+            tokio::spawn(answer_searches(ssdp, location)),
+            tokio::spawn(serve(http, state.clone())),
+        ];
+        FakeIgd { addr, state, tasks }
+    }
+
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    // Refuse mappings with a lease duration, like many gateways do (error 725).
+    pub fn permanent_only(&self) {
+        self.state.lock().unwrap().permanent_only = true;
+    }
+
+    // Answer every action with the given UPnP error code, 0 going back to normal.
+    pub fn fail_with(&self, error: u16) {
+        self.state.lock().unwrap().error = error;
+    }
+
+    pub fn set_public_ip(&self, public_ip: Ipv4Addr) {
+        self.state.lock().unwrap().public_ip = public_ip;
+    }
+
+    // Make a public port used by another client, so that mapping it conflicts (error 718).
+    pub fn take_port(&self, protocol: &str, port: u16) {
+        self.state
+            .lock()
+            .unwrap()
+            .taken
+            .push((protocol.to_owned(), port));
+    }
+
+    // Add a permanent mapping made by another program, as for the given internal client.
+    pub fn add_mapping(&self, protocol: &str, public: u16, internal: u16, client: &str, description: &str) {
+        let mapping = (internal, 0, client.to_owned(), description.to_owned());
+        self.state
+            .lock()
+            .unwrap()
+            .mappings
+            .insert((protocol.to_owned(), public), mapping);
+    }
+
+    // Current mappings, as (protocol, public port, internal port, lease duration), sorted.
+    pub fn mappings(&self) -> Vec<(String, u16, u16, u32)> {
+        let state = self.state.lock().unwrap();
+        let mut mappings: Vec<_> = state
+            .mappings
+            .iter()
+            .map(|((protocol, public), (internal, lease, ..))| {
+                (protocol.clone(), *public, *internal, *lease)
+            })
+            .collect();
+        mappings.sort();
+        mappings
+    }
+
+    // Actions invoked so far, in order.
+    pub fn actions(&self) -> Vec<Action> {
+        self.state.lock().unwrap().actions.clone()
+    }
+}
+
+impl Drop for FakeIgd {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn answer_searches(socket: UdpSocket, location: String) {
+    let mut buf = [0; 2048];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        let request = String::from_utf8_lossy(&buf[..len]);
+        if !request.starts_with("M-SEARCH") || !request.contains("ssdp:discover") {
+            continue;
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nEXT:\r\nLOCATION: {}\r\nSERVER: Fake/1.0 UPnP/1.1 FakeIgd/1.0\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nUSN: uuid:fake::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+            location
+        );
+        socket.send_to(response.as_bytes(), from).await.unwrap();
+    }
+}
+
+async fn serve(listener: TcpListener, state: Arc<Mutex<State>>) {
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(handle(stream, state.clone()));
+    }
+}
+
+// Read one HTTP request and answer it, closing the connection.
+async fn handle(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+    let mut request = vec![];
+    let mut buf = [0; 4096];
+    let (head, body) = loop {
+        let len = stream.read(&mut buf).await.unwrap();
+        if len == 0 {
+            return;
+        }
+        request.extend_from_slice(&buf[..len]);
+        let text = String::from_utf8_lossy(&request).into_owned();
+        let Some((head, body)) = text.split_once("\r\n\r\n") else {
+            continue;
+        };
+        let length = header(head, "content-length").map_or(0, |l| l.parse().unwrap());
+        if body.len() >= length {
+            break (head.to_owned(), body.to_owned());
+        }
+    };
+    let (status, body) = match head.split(' ').take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/rootDesc.xml"] => ("200 OK", description()),
+        ["POST", CONTROL_PATH] => control(&head, &body, &mut state.lock().unwrap()),
+        _ => ("404 Not Found", String::new()),
+    };
+    // Descriptions are sent chunked, as some gateways do.
+    let response = if head.starts_with("GET ") {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/xml\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+            status,
+            body.len(),
+            body
+        )
+    } else {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    };
+    stream.write_all(response.as_bytes()).await.unwrap();
+    stream.shutdown().await.unwrap();
+}
+
+// Description of the device, with the WAN connection service nested as in real gateways.
+fn description() -> String {
+    format!(
+        "<?xml version=\"1.0\"?>\r\n<root xmlns=\"urn:schemas-upnp-org:device-1-0\"><specVersion><major>1</major><minor>1</minor></specVersion><device><deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType><serviceList><service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType><controlURL>/ctl/L3F</controlURL></service></serviceList><deviceList><device><deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType><deviceList><device><deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType><serviceList><service><serviceType>{}</serviceType><serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId><controlURL>{}</controlURL><eventSubURL>/evt/IPConn</eventSubURL><SCPDURL>/WANIPCn.xml</SCPDURL></service></serviceList></device></deviceList></device></deviceList></device></root>\r\n",
+        SERVICE_TYPE, CONTROL_PATH
+    )
+}
+
+// Invoke a SOAP action, returning the HTTP status and body of the response.
+fn control(head: &str, body: &str, state: &mut State) -> (&'static str, String) {
+    let action = header(head, "soapaction")
+        .and_then(|a| a.trim_matches('"').split_once('#'))
+        .map(|(_, name)| name.to_owned())
+        .unwrap_or_default();
+    let args = arguments(body, &action);
+    let action = Action { name: action, args };
+    state.actions.push(action.clone());
+    if state.error != 0 {
+        return fault(state.error);
+    }
+    let arg = |name| action.arg(name);
+    match action.name.as_str() {
+        "GetExternalIPAddress" => respond(
+            &action.name,
+            &format!(
+                "<NewExternalIPAddress>{}</NewExternalIPAddress>",
+                state.public_ip
+            ),
+        ),
+        "AddPortMapping" => {
+            let key = (
+                arg("NewProtocol").to_owned(),
+                arg("NewExternalPort").parse().unwrap(),
+            );
+            let internal = arg("NewInternalPort").parse().unwrap();
+            let lease = arg("NewLeaseDuration").parse().unwrap();
+            if state.taken.contains(&key) {
+                return fault(718);
+            }
+            if state.permanent_only && lease != 0 {
+                return fault(725);
+            }
+            let client = arg("NewInternalClient").to_owned();
+            let description = arg("NewPortMappingDescription").to_owned();
+            state.mappings.insert(key, (internal, lease, client, description));
+            respond(&action.name, "")
+        }
+        "DeletePortMapping" => {
+            let key = (
+                arg("NewProtocol").to_owned(),
+                arg("NewExternalPort").parse().unwrap(),
+            );
+            match state.mappings.remove(&key) {
+                Some(_) => respond(&action.name, ""),
+                None => fault(714),
+            }
+        }
+        "GetGenericPortMappingEntry" => {
+            let mut mappings: Vec<_> = state.mappings.iter().collect();
+            mappings.sort();
+            let index: usize = arg("NewPortMappingIndex").parse().unwrap();
+            let Some(((protocol, public), (internal, lease, client, description))) = mappings.get(index)
+            else {
+                return fault(713);
+            };
+            respond(
+                &action.name,
+                &format!(
+                    "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort><NewProtocol>{}</NewProtocol><NewInternalPort>{}</NewInternalPort><NewInternalClient>{}</NewInternalClient><NewEnabled>1</NewEnabled><NewPortMappingDescription>{}</NewPortMappingDescription><NewLeaseDuration>{}</NewLeaseDuration>",
+                    public, protocol, internal, client, description, lease
+                ),
+            )
+        }
+        _ => fault(401),
+    }
+}
+
+// Function to get the arguments of an action, which are the children of its element.
+fn arguments(body: &str, action: &str) -> HashMap<String, String> {
+    let mut args = HashMap::new();
+    let Some(start) = body.find(&format!(":{} ", action)) else {
+        return args;
+    };
+    let mut rest = &body[start..];
+    rest = &rest[rest.find('>').unwrap() + 1..];
+    while let Some(open) = rest.strip_prefix('<').filter(|r| !r.starts_with('/')) {
+        let (name, after) = open.split_once('>').unwrap();
+        let (value, after) = after.split_once(&format!("</{}>", name)).unwrap();
+        args.insert(name.to_owned(), value.to_owned());
+        rest = after;
+    }
+    args
+}
+
+fn respond(action: &str, args: &str) -> (&'static str, String) {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{}Response xmlns:u=\"{}\">{}</u:{}Response></s:Body></s:Envelope>\r\n",
+        action, SERVICE_TYPE, args, action
+    );
+    ("200 OK", body)
+}
+
+fn fault(code: u16) -> (&'static str, String) {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>{}</errorCode><errorDescription>Fake error</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>\r\n",
+        code
+    );
+    ("500 Internal Server Error", body)
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
@@ -0,0 +1,40 @@
+// Every test crate uses a different part of these helpers.
+#![allow(dead_code)]
+
+pub mod gateway;
+pub mod igd;
+
+use natpmp_setup::gateway::GatewaySpec;
+use natpmp_setup::mapping::MappingSpec;
+use natpmp_setup::{Config, Event};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast::Receiver;
+use tokio::time;
+
+// Last byte of the loopback address of the next fake gateway, so that tests running in
+// parallel each get their own.
+static NEXT_ADDR: AtomicU8 = AtomicU8::new(2);
+
+// Function to get a loopback address for a fake gateway.
+pub fn next_addr() -> Ipv4Addr {
+    Ipv4Addr::new(127, 0, 0, NEXT_ADDR.fetch_add(1, Ordering::Relaxed))
+}
+
+// Configuration of a forwarder for the given gateway and `NAME[:PROTOCOL...]` mappings.
+pub fn config(gateway: Ipv4Addr, mappings: &[&str]) -> Config {
+    Config {
+        gateway: GatewaySpec::Address(gateway),
+        mappings: mappings.iter().map(|m| m.parse().unwrap()).collect::<Vec<MappingSpec>>(),
+        ..Default::default()
+    }
+}
+
+// Function to wait for the next event of a forwarder, failing the test if none comes.
+pub async fn next_event(events: &mut Receiver<Event>) -> Event {
+    time::timeout(Duration::from_secs(600), events.recv())
+        .await
+        .expect("No event received")
+        .expect("Forwarder stopped")
+}
@@ -0,0 +1,602 @@
+mod common;
+
+use common::{config, next_event};
+use common::gateway::{FakeGateway, TCP, UDP};
+use natpmp_setup::backend::{BackendKind, Session};
+use natpmp_setup::client::{self, query_gateway, query_port, PortRequest};
+use natpmp_setup::codec::Protocol;
+use natpmp_setup::gateway::GatewaySpec;
+use natpmp_setup::renewal::{RenewAt, Renewal};
+use natpmp_setup::{Event, PortForwarder};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::time;
+
+#[tokio::test(start_paused = true)]
+async fn creates_mappings_and_releases_them() {
+    let gw = FakeGateway::start().await;
+    let forwarder = PortForwarder::start(config(gw.addr(), &["both", "web:tcp"]))
+        .await
+        .unwrap();
+
+    let state = forwarder.state();
+    assert_eq!(state.public_ip, Ipv4Addr::new(203, 0, 113, 1));
+    let [both, web] = &state.mappings[..] else {
+        panic!("Unexpected mappings {:?}", state.mappings);
+    };
+    assert_eq!(both.public_port(Protocol::TCP), Some(40000));
+    assert_eq!(both.public_port(Protocol::UDP), Some(40000));
+    assert_eq!(web.public_port(Protocol::TCP), Some(40001));
+    assert_eq!(web.public_port(Protocol::UDP), None);
+    assert_eq!(gw.mappings(), [(UDP, 40000), (TCP, 40000), (TCP, 40001)]);
+
+    forwarder.shutdown().await.unwrap();
+    assert_eq!(gw.mappings(), []);
+}
+
+#[tokio::test(start_paused = true)]
+async fn renews_mappings_before_they_expire() {
+    let gw = FakeGateway::start().await;
+    gw.set_max_lifetime(60);
+    let forwarder = PortForwarder::start(config(gw.addr(), &["both"]))
+        .await
+        .unwrap();
+    let mut events = forwarder.subscribe();
+
+    time::sleep(Duration::from_secs(615)).await;
+    assert_eq!(gw.mappings(), [(UDP, 40000), (TCP, 40000)]);
+    // Renewed every 30 seconds, at half the granted lifetime.
+    let renewals = gw
+        .requests()
+        .iter()
+        .filter(|r| r.opcode == TCP && r.external == 40000)
+        .count();
+    assert_eq!(renewals, 20);
+    assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Empty);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn renews_at_a_margin_before_expiry() {
+    let gw = FakeGateway::start().await;
+    gw.set_max_lifetime(60);
+    let mut config = config(gw.addr(), &["web:tcp"]);
+    config.renewal.at = RenewAt::Margin(Duration::from_secs(10));
+    let forwarder = PortForwarder::start(config).await.unwrap();
+
+    time::sleep(Duration::from_secs(615)).await;
+    let requests: Vec<_> = gw.requests().into_iter().filter(|r| r.opcode == TCP).collect();
+    // Renewed every 50 seconds, and still asking for the configured lifetime.
+    assert_eq!(requests.len(), 1 + 12);
+    assert!(requests.iter().all(|r| r.lifetime == 360));
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn renews_at_half_a_lifetime_shorter_than_the_margin() {
+    let gw = FakeGateway::start().await;
+    gw.set_max_lifetime(60);
+    let mut config = config(gw.addr(), &["web:tcp"]);
+    config.renewal.at = RenewAt::Margin(Duration::from_secs(90));
+    let forwarder = PortForwarder::start(config).await.unwrap();
+
+    time::sleep(Duration::from_secs(615)).await;
+    assert_eq!(gw.mappings(), [(TCP, 40000)]);
+    let renewals = gw.requests().iter().filter(|r| r.opcode == TCP).count() - 1;
+    assert_eq!(renewals, 20);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn renews_with_jitter_before_expiry() {
+    let gw = FakeGateway::start().await;
+    gw.set_max_lifetime(60);
+    let mut config = config(gw.addr(), &["web:tcp"]);
+    config.renewal = Renewal {
+        at: RenewAt::Fraction(0.9),
+        jitter: 0.5,
+    };
+    let forwarder = PortForwarder::start(config).await.unwrap();
+
+    // Renewals come 27 to 54 seconds apart, never letting the mapping expire.
+    for _ in 0..615 {
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(gw.mappings(), [(TCP, 40000)]);
+    }
+    let renewals = gw.requests().iter().filter(|r| r.opcode == TCP).count() - 1;
+    assert!((11..=22).contains(&renewals), "{} renewals", renewals);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn retransmits_dropped_requests() {
+    let gw = FakeGateway::start().await;
+    gw.drop_next(3);
+    let forwarder = PortForwarder::start(config(gw.addr(), &["web:tcp"]))
+        .await
+        .unwrap();
+
+    let opcodes: Vec<u8> = gw.requests().iter().map(|r| r.opcode).collect();
+    assert_eq!(opcodes, [0, 0, 0, 0, TCP]);
+    assert_eq!(forwarder.state().mappings[0].port(), Some(40000));
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn waits_for_delayed_replies() {
+    let gw = FakeGateway::start().await;
+    gw.delay_replies(Duration::from_secs(1));
+    let forwarder = PortForwarder::start(config(gw.addr(), &["both"]))
+        .await
+        .unwrap();
+
+    // Replies to the retransmissions arrive late too, and are ignored.
+    let m = &forwarder.state().mappings[0];
+    assert_eq!(m.public_port(Protocol::TCP), Some(40000));
+    assert_eq!(m.public_port(Protocol::UDP), Some(40000));
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn publishes_port_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let plain = dir.path().join("natpmp.csv");
+    let json = dir.path().join("natpmp.json");
+    let gw = FakeGateway::start().await;
+    let mut config = config(gw.addr(), &["both"]);
+    config.outputs = vec![
+        plain.to_str().unwrap().parse().unwrap(),
+        format!("json:{}", json.display()).parse().unwrap(),
+    ];
+    gw.set_max_lifetime(60);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    let mut events = forwarder.subscribe();
+    let pid = std::process::id();
+    assert_eq!(
+        fs::read_to_string(&plain).unwrap(),
+        format!("both,{},40000,40000,203.0.113.1\n", pid)
+    );
+
+    // The renewal gets another port, which is given up on for a new mapping.
+    gw.change_ports();
+    let Event::Lost { mapping, .. } = next_event(&mut events).await else {
+        panic!("Expected the mapping to be lost");
+    };
+    assert_eq!(mapping.port(), Some(40000));
+    let Event::PortChanged { old, new, .. } = next_event(&mut events).await else {
+        panic!("Expected the port to change");
+    };
+    assert_eq!(old.port(), Some(40000));
+    let port = new.port().unwrap();
+    assert_ne!(port, 40000);
+    assert_eq!(new.public_port(Protocol::UDP), Some(port));
+    assert_eq!(forwarder.state().mappings[0].port(), Some(port));
+    assert_eq!(
+        fs::read_to_string(&plain).unwrap(),
+        format!("both,{},{},{},203.0.113.1\n", pid, port, port)
+    );
+    let content = fs::read_to_string(&json).unwrap();
+    assert!(content.contains(&format!("\"tcp\": {}", port)), "{}", content);
+
+    forwarder.shutdown().await.unwrap();
+    assert!(!plain.exists() && !json.exists());
+}
+
+// Function to read the (name, protocol, public port) entries of a state file.
+fn saved_ports(path: &std::path::Path) -> Vec<(String, String, u64)> {
+    let state: serde_json::Value = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    state["ports"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| {
+            (
+                p["name"].as_str().unwrap().to_owned(),
+                p["protocol"].as_str().unwrap().to_owned(),
+                p["public_port"].as_u64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test(start_paused = true)]
+async fn keeps_the_public_ports_across_restarts() {
+    let dir = tempfile::tempdir().unwrap();
+    let gw = FakeGateway::start().await;
+    let mut config = config(gw.addr(), &["both", "web:tcp"]);
+    config.state_file = Some(dir.path().join("state.json"));
+    let forwarder = PortForwarder::start(config.clone()).await.unwrap();
+    forwarder.shutdown().await.unwrap();
+    let saved = [
+        ("both".to_owned(), "tcp".to_owned(), 40000),
+        ("both".to_owned(), "udp".to_owned(), 40000),
+        ("web".to_owned(), "tcp".to_owned(), 40001),
+    ];
+    assert_eq!(saved_ports(&dir.path().join("state.json")), saved);
+
+    // New mappings would get the next ports.
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    let ports: Vec<_> = forwarder.state().mappings.iter().map(|m| m.port()).collect();
+    assert_eq!(ports, [Some(40000), Some(40001)]);
+    assert_eq!(forwarder.state().mappings[0].public_port(Protocol::UDP), Some(40000));
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn requests_new_ports_when_the_saved_ones_are_taken() {
+    let dir = tempfile::tempdir().unwrap();
+    let state_file = dir.path().join("state.json");
+    let gw = FakeGateway::start().await;
+    let mut config = config(gw.addr(), &["web:tcp"]);
+    config.state_file = Some(state_file.clone());
+    let forwarder = PortForwarder::start(config.clone()).await.unwrap();
+    forwarder.shutdown().await.unwrap();
+
+    gw.take(TCP, 40000);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    let port = forwarder.state().mappings[0].port().unwrap();
+    assert_ne!(port, 40000);
+    assert_eq!(saved_ports(&state_file), [("web".to_owned(), "tcp".to_owned(), port.into())]);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn asks_for_the_preferred_public_port() {
+    let gw = FakeGateway::start().await;
+    let forwarder = PortForwarder::start(config(gw.addr(), &["web:tcp:::45000"]))
+        .await
+        .unwrap();
+    assert_eq!(forwarder.state().mappings[0].port(), Some(45000));
+    assert_eq!(gw.requests()[1].external, 45000);
+    forwarder.shutdown().await.unwrap();
+
+    // Unless told otherwise, another port is accepted when it is taken.
+    gw.take(TCP, 45000);
+    let forwarder = PortForwarder::start(config(gw.addr(), &["web:tcp:::45000"]))
+        .await
+        .unwrap();
+    assert_eq!(forwarder.state().mappings[0].port(), Some(40000));
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn retries_within_the_allowed_range() {
+    let gw = FakeGateway::start().await;
+    gw.take(TCP, 45000);
+    gw.take(TCP, 45001);
+    let forwarder = PortForwarder::start(config(gw.addr(), &["web:tcp:::45000-45009:retry"]))
+        .await
+        .unwrap();
+    assert_eq!(forwarder.state().mappings[0].port(), Some(45002));
+    // The ports granted outside of the range were given back.
+    assert_eq!(gw.mappings(), [(TCP, 45002)]);
+    let suggested: Vec<_> = gw
+        .requests()
+        .iter()
+        .filter(|r| r.lifetime > 0)
+        .map(|r| r.external)
+        .collect();
+    assert_eq!(suggested, [45000, 45001, 45002]);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn fails_when_the_preferred_port_is_taken() {
+    let gw = FakeGateway::start().await;
+    gw.take(TCP, 45000);
+    let e = PortForwarder::start(config(gw.addr(), &["web:tcp:::45000:fail"]))
+        .await
+        .err()
+        .unwrap();
+    assert!(format!("{:#}", e).contains("instead of a port in 45000-45000"), "{:#}", e);
+    assert_eq!(gw.mappings(), []);
+}
+
+#[tokio::test(start_paused = true)]
+async fn fails_to_start_without_mappings() {
+    let gw = FakeGateway::start().await;
+    let e = PortForwarder::start(config(gw.addr(), &[]))
+        .await
+        .err()
+        .unwrap();
+    assert!(format!("{:#}", e).contains("No mapping is declared"), "{:#}", e);
+    assert_eq!(gw.requests(), []);
+}
+
+#[tokio::test(start_paused = true)]
+async fn recreates_mappings_after_an_epoch_reset() {
+    let gw = FakeGateway::start().await;
+    gw.set_max_lifetime(60);
+    let forwarder = PortForwarder::start(config(gw.addr(), &["web:tcp", "game:udp:0:120"]))
+        .await
+        .unwrap();
+    let mut events = forwarder.subscribe();
+
+    gw.reset_epoch();
+    gw.set_public_ip(Ipv4Addr::new(198, 51, 100, 7));
+    let Event::PublicIpChanged { old, new } = next_event(&mut events).await else {
+        panic!("Expected the public IP to change");
+    };
+    assert_eq!(old, Ipv4Addr::new(203, 0, 113, 1));
+    assert_eq!(new, Ipv4Addr::new(198, 51, 100, 7));
+    // Both mappings are recreated at once, on their previous ports.
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(gw.mappings(), [(UDP, 40001), (TCP, 40000)]);
+    assert_eq!(forwarder.state().public_ip, new);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn reports_every_result_code() {
+    let gw = FakeGateway::start().await;
+    let mut n = client::connect(
+        &GatewaySpec::Address(gw.addr()),
+        None,
+        BackendKind::NatPmp,
+        &Session::default(),
+    )
+    .await
+    .unwrap();
+    for (code, error) in [
+        (1, "UNSUPPORTEDVERSION"),
+        (2, "NOTAUTHORIZED"),
+        (3, "NETWORKFAILURE"),
+        (4, "OUTOFRESOURCES"),
+        (5, "UNSUPPORTEDOPCODE"),
+        (6, "UNDEFINEDERROR"),
+    ] {
+        gw.fail_with(code);
+        let e = query_gateway(&mut n).await.unwrap_err();
+        assert!(e.to_string().contains(error), "{}: {}", code, e);
+        let e = query_port(&mut n, Protocol::TCP, 0, 60, &PortRequest::any())
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains(error), "{}: {}", code, e);
+    }
+    gw.fail_with(0);
+    query_gateway(&mut n).await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn fails_to_start_when_the_gateway_refuses() {
+    let gw = FakeGateway::start().await;
+    gw.fail_with(2);
+    let e = PortForwarder::start(config(gw.addr(), &["both"]))
+        .await
+        .err()
+        .unwrap();
+    assert!(format!("{:#}", e).contains("NOTAUTHORIZED"), "{:#}", e);
+    assert!(format!("{:#}", e).contains("does not support port forwarding"), "{:#}", e);
+}
+
+#[tokio::test(start_paused = true)]
+async fn stops_when_the_gateway_refuses_later() {
+    let gw = FakeGateway::start().await;
+    gw.set_max_lifetime(60);
+    let forwarder = PortForwarder::start(config(gw.addr(), &["both"]))
+        .await
+        .unwrap();
+    gw.fail_with(2);
+    time::timeout(Duration::from_secs(60), forwarder.stopped())
+        .await
+        .expect("Forwarder kept running");
+    let e = forwarder.shutdown().await.unwrap_err();
+    assert!(format!("{:#}", e).contains("does not support port forwarding"), "{:#}", e);
+}
+
+#[tokio::test(start_paused = true)]
+async fn keeps_retrying_on_network_failure() {
+    let gw = FakeGateway::start().await;
+    let mut n = client::connect(
+        &GatewaySpec::Address(gw.addr()),
+        None,
+        BackendKind::NatPmp,
+        &Session::default(),
+    )
+    .await
+    .unwrap();
+    gw.fail_with(3);
+    let query = tokio::spawn(async move { query_gateway(&mut n).await });
+    time::sleep(Duration::from_secs(10)).await;
+    gw.fail_with(0);
+    query.await.unwrap().unwrap();
+    assert!(gw.requests().len() >= 4, "{:?}", gw.requests());
+}
+
+#[tokio::test(start_paused = true)]
+async fn backs_off_long_when_the_gateway_runs_out_of_ports() {
+    let gw = FakeGateway::start().await;
+    gw.set_max_lifetime(60);
+    let forwarder = PortForwarder::start(config(gw.addr(), &["web:tcp"]))
+        .await
+        .unwrap();
+    let mut events = forwarder.subscribe();
+
+    gw.fail_with(4);
+    let Event::Lost { .. } = next_event(&mut events).await else {
+        panic!("Expected the mapping to be lost");
+    };
+    time::sleep(Duration::from_secs(1)).await;
+    assert!(forwarder.state().is_degraded());
+    // No retry in the next minutes, unlike other failures.
+    let mapping_requests = || gw.requests().iter().filter(|r| r.opcode == TCP).count();
+    let requests = mapping_requests();
+    time::sleep(Duration::from_secs(400)).await;
+    assert_eq!(mapping_requests(), requests);
+
+    gw.fail_with(0);
+    let Event::Created { .. } = next_event(&mut events).await else {
+        panic!("Expected the mapping to be recreated");
+    };
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn degrades_and_recovers() {
+    let dir = tempfile::tempdir().unwrap();
+    let plain = dir.path().join("natpmp.csv");
+    let json = dir.path().join("natpmp.json");
+    let gw = FakeGateway::start().await;
+    let mut config = config(gw.addr(), &["both"]);
+    config.outputs = vec![
+        plain.to_str().unwrap().parse().unwrap(),
+        format!("json:{}", json.display()).parse().unwrap(),
+    ];
+    gw.set_max_lifetime(60);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    let mut events = forwarder.subscribe();
+    let pid = std::process::id();
+
+    // The forwarder keeps running while the gateway fails, with the ports marked invalid.
+    gw.fail_with(3);
+    let Event::Lost { .. } = next_event(&mut events).await else {
+        panic!("Expected the mapping to be lost");
+    };
+    // The new mapping is requested again and again before giving up.
+    time::sleep(Duration::from_secs(130)).await;
+    assert!(forwarder.state().is_degraded());
+    assert_eq!(fs::read_to_string(&plain).unwrap(), format!("both,{},,,203.0.113.1\n", pid));
+    let content = fs::read_to_string(&json).unwrap();
+    assert!(content.contains("\"status\": \"degraded\""), "{}", content);
+
+    // It recovers on its own once the gateway does.
+    time::sleep(Duration::from_secs(60)).await;
+    gw.fail_with(0);
+    let Event::Created { mapping, .. } = next_event(&mut events).await else {
+        panic!("Expected the mapping to be recreated");
+    };
+    let port = mapping.port().unwrap();
+    assert!(!forwarder.state().is_degraded());
+    assert_eq!(
+        fs::read_to_string(&plain).unwrap(),
+        format!("both,{},{},{},203.0.113.1\n", pid, port, port)
+    );
+    let content = fs::read_to_string(&json).unwrap();
+    assert!(content.contains("\"status\": \"ok\""), "{}", content);
+
+    forwarder.shutdown().await.unwrap();
+    assert_eq!(gw.mappings(), []);
+}
+
+#[tokio::test(start_paused = true)]
+async fn starts_degraded_while_the_gateway_is_unreachable() {
+    let dir = tempfile::tempdir().unwrap();
+    let plain = dir.path().join("natpmp.csv");
+    let gw = FakeGateway::start().await;
+    let mut config = config(gw.addr(), &["both", "web:tcp"]);
+    config.outputs = vec![plain.to_str().unwrap().parse().unwrap()];
+    // The public address request is never answered.
+    gw.drop_next(9);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    let mut events = forwarder.subscribe();
+    let pid = std::process::id();
+
+    // No mapping was requested, the gateway being unreachable.
+    assert!(gw.requests().iter().all(|r| r.opcode == 0));
+    assert!(forwarder.state().mappings.iter().all(|m| m.is_lost()));
+    assert_eq!(
+        fs::read_to_string(&plain).unwrap(),
+        format!("both,{},,,0.0.0.0\nweb,{},,,0.0.0.0\n", pid, pid)
+    );
+
+    for _ in 0..2 {
+        let Event::Created { mapping, public_ip } = next_event(&mut events).await else {
+            panic!("Expected a mapping to be created");
+        };
+        assert!(mapping.port().is_some());
+        assert_eq!(public_ip, Ipv4Addr::new(203, 0, 113, 1));
+    }
+    let state = forwarder.state();
+    assert!(!state.is_degraded());
+    assert!(state.mappings.iter().all(|m| m.port().is_some()));
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn starts_degraded_on_network_failure() {
+    let gw = FakeGateway::start().await;
+    gw.fail_with(3);
+    let forwarder = PortForwarder::start(config(gw.addr(), &["web:tcp"]))
+        .await
+        .unwrap();
+    let mut events = forwarder.subscribe();
+    assert!(forwarder.state().is_degraded());
+
+    gw.fail_with(0);
+    let Event::Created { mapping, public_ip } = next_event(&mut events).await else {
+        panic!("Expected the mapping to be created");
+    };
+    assert_eq!(mapping.port(), Some(40000));
+    assert_eq!(public_ip, Ipv4Addr::new(203, 0, 113, 1));
+    forwarder.shutdown().await.unwrap();
+    assert_eq!(gw.mappings(), []);
+}
+
+#[tokio::test(start_paused = true)]
+async fn follows_public_ip_announcements() {
+    let gw = FakeGateway::start().await;
+    let other = FakeGateway::start().await;
+    let forwarder = PortForwarder::start(config(gw.addr(), &["web:tcp"]))
+        .await
+        .unwrap();
+    let mut events = forwarder.subscribe();
+    let requests = gw.requests().len();
+
+    // Only the gateway may announce its address.
+    other.set_public_ip(Ipv4Addr::new(192, 0, 2, 66));
+    other.announce().await;
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(forwarder.state().public_ip, Ipv4Addr::new(203, 0, 113, 1));
+
+    gw.set_public_ip(Ipv4Addr::new(198, 51, 100, 7));
+    gw.announce().await;
+    let Event::PublicIpChanged { old, new } = next_event(&mut events).await else {
+        panic!("Expected the public IP to change");
+    };
+    assert_eq!(old, Ipv4Addr::new(203, 0, 113, 1));
+    assert_eq!(new, Ipv4Addr::new(198, 51, 100, 7));
+    assert_eq!(forwarder.state().public_ip, new);
+    // The mapping is renewed right away, and only once for repeated announcements.
+    gw.announce().await;
+    time::sleep(Duration::from_secs(1)).await;
+    let renewals = gw.requests()[requests..]
+        .iter()
+        .filter(|r| r.opcode == TCP)
+        .count();
+    assert_eq!(renewals, 1);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn refreshes_the_public_ip() {
+    let gw = FakeGateway::start().await;
+    gw.set_max_lifetime(500);
+    let mut config = config(gw.addr(), &["web:tcp"]);
+    config.public_ip_interval = Duration::from_secs(60);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    let mut events = forwarder.subscribe();
+
+    // Without any announcement, the new address is found by the next query.
+    let start = time::Instant::now();
+    gw.set_public_ip(Ipv4Addr::new(198, 51, 100, 7));
+    let Event::PublicIpChanged { new, .. } = next_event(&mut events).await else {
+        panic!("Expected the public IP to change");
+    };
+    assert_eq!(new, Ipv4Addr::new(198, 51, 100, 7));
+    assert!(start.elapsed() <= Duration::from_secs(61));
+
+    // It is queried again as soon as a mapping gets another port, at 250s, between two
+    // periodic queries.
+    gw.change_ports();
+    let Event::Lost { .. } = next_event(&mut events).await else {
+        panic!("Expected the mapping to be lost");
+    };
+    let Event::PortChanged { .. } = next_event(&mut events).await else {
+        panic!("Expected the port to change");
+    };
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(gw.requests().last().unwrap().opcode, 0);
+    forwarder.shutdown().await.unwrap();
+}
@@ -0,0 +1,100 @@
+use natpmp_setup::gateway::GatewaySpec;
+use std::net::Ipv4Addr;
+
+// A host with a LAN default route, a more expensive Wi-Fi one, an OpenVPN tunnel routing
+// everything through 10.8.0.1, WireGuard interfaces without gateway, on a subnet and to a
+// peer, and one routing everything on-link.
+const ROUTES: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+wlan0\t00000000\t0100A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+tun0\t00000000\t0100080A\t0003\t0\t0\t0\t00000080\t0\t0\t0
+tun0\t00000080\t0100080A\t0003\t0\t0\t0\t00000080\t0\t0\t0
+tun0\t0000080A\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+tun0\t0001080A\t0200080A\t0003\t0\t0\t0\t00FFFFFF\t0\t0\t0
+wg0\t0000020A\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+wg1\t00000080\t00000000\t0001\t0\t0\t0\t00000080\t0\t0\t0
+wg1\t0100400A\t00000000\t0001\t0\t0\t0\tFFFFFFFF\t0\t0\t0
+wg2\t00000000\t00000000\t0001\t0\t0\t0\t00000000\t0\t0\t0
+";
+
+fn resolve(spec: &str, routes: &str) -> anyhow::Result<Ipv4Addr> {
+    spec.parse::<GatewaySpec>().unwrap().resolve_from(routes)
+}
+
+#[test]
+fn parses_gateway_specs() {
+    assert_eq!(
+        "10.2.0.1".parse::<GatewaySpec>().unwrap(),
+        GatewaySpec::Address(Ipv4Addr::new(10, 2, 0, 1))
+    );
+    assert_eq!("auto".parse::<GatewaySpec>().unwrap(), GatewaySpec::Auto);
+    assert_eq!(
+        "auto:wg0".parse::<GatewaySpec>().unwrap(),
+        GatewaySpec::Interface("wg0".to_owned())
+    );
+    for spec in ["", "10.2.0", "gateway", "auto:", "auto:a b"] {
+        let e = spec.parse::<GatewaySpec>().unwrap_err();
+        assert!(e.to_string().contains("nvalid"), "{:?}: {}", spec, e);
+    }
+}
+
+#[test]
+fn picks_the_default_route_with_the_lowest_metric() {
+    assert_eq!(
+        resolve("auto", ROUTES).unwrap(),
+        Ipv4Addr::new(192, 168, 1, 1)
+    );
+    assert_eq!(
+        resolve("auto:wlan0", ROUTES).unwrap(),
+        Ipv4Addr::new(192, 168, 0, 1)
+    );
+    // Fixed addresses do not need the table.
+    assert_eq!(resolve("10.2.0.1", "").unwrap(), Ipv4Addr::new(10, 2, 0, 1));
+}
+
+#[test]
+fn picks_the_widest_route_of_an_interface() {
+    assert_eq!(
+        resolve("auto:tun0", ROUTES).unwrap(),
+        Ipv4Addr::new(10, 8, 0, 1)
+    );
+}
+
+#[test]
+fn guesses_the_gateway_of_point_to_point_interfaces() {
+    assert_eq!(
+        resolve("auto:wg0", ROUTES).unwrap(),
+        Ipv4Addr::new(10, 2, 0, 1)
+    );
+    assert_eq!(
+        resolve("auto:wg1", ROUTES).unwrap(),
+        Ipv4Addr::new(10, 64, 0, 1)
+    );
+}
+
+#[test]
+fn explains_why_no_gateway_was_found() {
+    let e = resolve("auto:wg2", ROUTES).unwrap_err();
+    assert!(
+        e.to_string()
+            .contains("No route on wg2 goes through a gateway"),
+        "{}",
+        e
+    );
+    let e = resolve("auto:tun1", ROUTES).unwrap_err();
+    assert!(e.to_string().contains("No route on tun1"), "{}", e);
+    let header = ROUTES.lines().next().unwrap();
+    let e = resolve("auto", header).unwrap_err();
+    assert!(e.to_string().contains("No default route"), "{}", e);
+    let e = resolve(
+        "auto",
+        &format!(
+            "{}\neth0\t0000000G\t0101A8C0\t0003\t0\t0\t0\t00000000\n",
+            header
+        ),
+    )
+    .unwrap_err();
+    assert!(format!("{:#}", e).contains("Invalid line 2"), "{:#}", e);
+}
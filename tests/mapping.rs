@@ -0,0 +1,41 @@
+use natpmp_setup::mapping::{MappingSpec, PortPolicy, Protocols};
+
+fn parse(spec: &str) -> MappingSpec {
+    spec.parse().unwrap()
+}
+
+#[test]
+fn parses_mapping_specs() {
+    assert_eq!(parse("default"), MappingSpec::default());
+    let spec = parse("web:tcp:8080:60");
+    assert_eq!(
+        (spec.protocols, spec.internal_port, spec.lifetime),
+        (Protocols::Tcp, 8080, 60)
+    );
+    // Empty fields keep their default.
+    let spec = parse("web::::45000");
+    assert_eq!((spec.protocols, spec.lifetime), (Protocols::Both, 360));
+    assert_eq!((spec.public_port, spec.public_ports.clone()), (45000, None));
+    assert_eq!(spec.preferred_port(), 45000);
+    let spec = parse("web:udp:::45000-45099:retry");
+    assert_eq!(
+        (spec.public_port, spec.public_ports.clone()),
+        (0, Some(45000..=45099))
+    );
+    assert_eq!(
+        (spec.preferred_port(), spec.policy),
+        (45000, PortPolicy::Retry)
+    );
+    for spec in [
+        "",
+        "web:sctp",
+        "web:tcp:http",
+        "web:tcp:80:0",
+        "web::::0",
+        "web::::45099-45000",
+        "web::::45000:maybe",
+        "web::::45000:fail:",
+    ] {
+        assert!(spec.parse::<MappingSpec>().is_err(), "{:?}", spec);
+    }
+}
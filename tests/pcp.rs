@@ -0,0 +1,254 @@
+mod common;
+
+use common::{config, next_event};
+use common::gateway::{FakeGateway, TCP, UDP};
+use natpmp_setup::backend::BackendKind;
+use natpmp_setup::codec::Protocol;
+use natpmp_setup::{Config, Event, PortForwarder};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::time;
+
+// Configuration of a forwarder speaking the given protocol.
+fn config_with(gw: &FakeGateway, backend: BackendKind, mappings: &[&str]) -> Config {
+    Config {
+        backend,
+        ..config(gw.addr(), mappings)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn maps_ports_with_pcp() {
+    let gw = FakeGateway::start().await;
+    gw.speak_pcp();
+    let config = config_with(&gw, BackendKind::Pcp, &["both", "web:tcp:8080"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+
+    // The public IP comes along with the mappings.
+    let state = forwarder.state();
+    assert_eq!(state.public_ip, Ipv4Addr::new(203, 0, 113, 1));
+    let [both, web] = &state.mappings[..] else {
+        panic!("Unexpected mappings {:?}", state.mappings);
+    };
+    // Internal port 0 is mapped to the same port as the public one.
+    let port = both.public_port(Protocol::TCP).unwrap();
+    assert!(port >= 49152, "{}", port);
+    assert_eq!(both.tcp.unwrap().private_port, port);
+    assert_eq!(both.public_port(Protocol::UDP), Some(port));
+    assert_eq!(web.tcp.unwrap().private_port, 8080);
+    assert_eq!(web.public_port(Protocol::TCP), Some(40000));
+    assert_eq!(gw.mappings().len(), 3);
+    assert!(gw.requests().iter().all(|r| r.version == 2));
+
+    forwarder.shutdown().await.unwrap();
+    assert_eq!(gw.mappings(), []);
+}
+
+#[tokio::test(start_paused = true)]
+async fn renews_pcp_mappings_with_their_nonce() {
+    let gw = FakeGateway::start().await;
+    gw.speak_pcp();
+    gw.set_max_lifetime(60);
+    let config = config_with(&gw, BackendKind::Pcp, &["web:tcp:8080"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    let mut events = forwarder.subscribe();
+    let nonces = gw.nonces();
+
+    // The gateway refuses renewals with another nonce than the mapping's.
+    time::sleep(Duration::from_secs(615)).await;
+    assert_eq!(gw.mappings(), [(TCP, 40000)]);
+    assert_eq!(gw.nonces(), nonces);
+    // A reply may only be read once the paused clock jumps to the first timeout of its
+    // request, which delays the renewal by about 3s.
+    let renewals = gw.requests().iter().filter(|r| r.opcode == TCP).count() - 1;
+    assert!((18..=20).contains(&renewals), "{}", renewals);
+    assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Empty);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn negotiates_pcp_when_the_gateway_speaks_it() {
+    let gw = FakeGateway::start().await;
+    gw.speak_pcp();
+    let config = config_with(&gw, BackendKind::Auto, &["web:udp:8080"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+
+    assert_eq!(forwarder.state().mappings[0].port(), Some(40000));
+    assert!(gw.requests().iter().all(|r| r.version == 2));
+    assert_eq!(gw.mappings(), [(UDP, 40000)]);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn falls_back_to_natpmp_on_a_version_0_reply() {
+    let gw = FakeGateway::start().await;
+    let config = config_with(&gw, BackendKind::Auto, &["web:tcp"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+
+    // The PCP probe is answered with UNSUPPORTEDVERSION, then the NAT-PMP one is answered.
+    let versions: Vec<u8> = gw.requests().iter().map(|r| r.version).collect();
+    assert_eq!(versions, [2, 0, 0, 0]);
+    assert_eq!(forwarder.state().public_ip, Ipv4Addr::new(203, 0, 113, 1));
+    assert_eq!(gw.mappings(), [(TCP, 40000)]);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn falls_back_to_natpmp_when_pcp_is_ignored() {
+    let gw = FakeGateway::start().await;
+    gw.drop_next(3);
+    let start = time::Instant::now();
+    let config = config_with(&gw, BackendKind::Auto, &["web:tcp"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+
+    assert!(start.elapsed() >= Duration::from_millis(1750));
+    let versions: Vec<u8> = gw.requests().iter().map(|r| r.version).collect();
+    assert_eq!(versions, [2, 2, 2, 0, 0, 0]);
+    assert_eq!(gw.mappings(), [(TCP, 40000)]);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn follows_the_public_ip_of_pcp_mappings() {
+    let gw = FakeGateway::start().await;
+    gw.speak_pcp();
+    gw.set_max_lifetime(60);
+    let config = config_with(&gw, BackendKind::Pcp, &["web:tcp:8080"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    let mut events = forwarder.subscribe();
+
+    // The next renewal tells the new address.
+    gw.set_public_ip(Ipv4Addr::new(198, 51, 100, 7));
+    let Event::PublicIpChanged { old, new } = next_event(&mut events).await else {
+        panic!("Expected the public IP to change");
+    };
+    assert_eq!(old, Ipv4Addr::new(203, 0, 113, 1));
+    assert_eq!(new, Ipv4Addr::new(198, 51, 100, 7));
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn recreates_mappings_on_a_pcp_announcement() {
+    let gw = FakeGateway::start().await;
+    gw.speak_pcp();
+    let config = config_with(&gw, BackendKind::Pcp, &["web:tcp:8080"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+
+    // The gateway reboots, announcing its new epoch.
+    gw.reset_epoch();
+    gw.announce().await;
+    // The mapping is renewed right away, long before it is due.
+    time::sleep(Duration::from_secs(10)).await;
+    assert_eq!(gw.mappings(), [(TCP, 40000)]);
+    assert_eq!(forwarder.state().mappings[0].port(), Some(40000));
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn fails_to_start_when_the_pcp_gateway_refuses() {
+    let gw = FakeGateway::start().await;
+    gw.speak_pcp();
+    gw.fail_with(2);
+    let config = config_with(&gw, BackendKind::Pcp, &["web:tcp:8080"]);
+    let Err(e) = PortForwarder::start(config).await else {
+        panic!("Expected the forwarder to fail");
+    };
+    let message = format!("{:#}", e);
+    assert!(message.contains("NOTAUTHORIZED"), "{}", message);
+    assert!(message.contains("does not support port forwarding"), "{}", message);
+}
+
+#[tokio::test(start_paused = true)]
+async fn fails_to_start_when_the_pcp_gateway_refuses_mappings() {
+    let gw = FakeGateway::start().await;
+    gw.speak_pcp();
+    gw.refuse_mappings(2);
+    let config = config_with(&gw, BackendKind::Pcp, &["web:tcp:8080"]);
+    let Err(e) = PortForwarder::start(config).await else {
+        panic!("Expected the forwarder to fail");
+    };
+    let message = format!("{:#}", e);
+    assert!(message.contains("does not support port forwarding"), "{}", message);
+    assert_eq!(gw.mappings(), []);
+}
+
+#[tokio::test(start_paused = true)]
+async fn keeps_pcp_nonces_across_reconnections() {
+    let gw = FakeGateway::start().await;
+    gw.speak_pcp();
+    let config = config_with(&gw, BackendKind::Pcp, &["web:tcp:8080:3600"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    let mut events = forwarder.subscribe();
+    let nonces = gw.nonces();
+
+    // The renewal and the request of new ports go unanswered, so the forwarder connects
+    // again, while the gateway still holds the mapping.
+    time::sleep(Duration::from_secs(1700)).await;
+    gw.drop_next(12);
+    let Event::Lost { .. } = next_event(&mut events).await else {
+        panic!("Expected the mapping to be lost");
+    };
+    let Event::Created { mapping, .. } = next_event(&mut events).await else {
+        panic!("Expected the mapping to be recreated");
+    };
+    assert_eq!(mapping.port(), Some(40000));
+    assert_eq!(gw.nonces(), nonces);
+    forwarder.shutdown().await.unwrap();
+    assert_eq!(gw.mappings(), []);
+}
+
+#[tokio::test(start_paused = true)]
+async fn restores_pcp_mappings_with_their_saved_nonce() {
+    let dir = tempfile::tempdir().unwrap();
+    let gw = FakeGateway::start().await;
+    gw.speak_pcp();
+    let config = Config {
+        state_file: Some(dir.path().join("state.json")),
+        ..config_with(&gw, BackendKind::Pcp, &["web:tcp:8080"])
+    };
+    let first = PortForwarder::start(config.clone()).await.unwrap();
+    let nonces = gw.nonces();
+
+    // A second run, as after a crash, takes the mapping over with the saved nonce.
+    let second = PortForwarder::start(config).await.unwrap();
+    let state = second.state();
+    assert!(!state.is_degraded());
+    assert_eq!(state.mappings[0].port(), Some(40000));
+    assert_eq!(gw.nonces(), nonces);
+    second.shutdown().await.unwrap();
+    assert_eq!(gw.mappings(), []);
+    first.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn retries_pcp_mappings_held_with_another_nonce() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.json");
+    let gw = FakeGateway::start().await;
+    gw.speak_pcp();
+    let config = Config {
+        state_file: Some(path.clone()),
+        ..config_with(&gw, BackendKind::Pcp, &["web:tcp:8080"])
+    };
+    let first = PortForwarder::start(config.clone()).await.unwrap();
+
+    // The state file of a previous version has no nonce, so the gateway refuses the mapping
+    // until it is released.
+    let mut saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    for port in saved["ports"].as_array_mut().unwrap() {
+        port.as_object_mut().unwrap().remove("nonce");
+    }
+    std::fs::write(&path, saved.to_string()).unwrap();
+    let second = PortForwarder::start(config).await.unwrap();
+    let mut events = second.subscribe();
+    assert!(second.state().is_degraded());
+    first.shutdown().await.unwrap();
+    let Event::Created { mapping, .. } = next_event(&mut events).await else {
+        panic!("Expected the mapping to be created");
+    };
+    assert!(mapping.port().is_some());
+    second.shutdown().await.unwrap();
+    assert_eq!(gw.mappings(), []);
+}
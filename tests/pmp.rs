@@ -0,0 +1,190 @@
+mod common;
+
+use common::gateway::{FakeGateway, TCP, UDP};
+use common::next_addr;
+use natpmp_setup::codec::{Body, Opcode, Protocol, Request, Response, NATPMP_PORT};
+use natpmp_setup::pmp::NatPmp;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+
+// NAT-PMP client over a socket connected to the gateway.
+async fn client(gateway: Ipv4Addr) -> NatPmp {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    socket.connect((gateway, NATPMP_PORT)).await.unwrap();
+    NatPmp::new(socket, gateway)
+}
+
+// A gateway whose replies the test writes itself.
+async fn scripted_gateway() -> (UdpSocket, Ipv4Addr) {
+    let addr = next_addr();
+    let socket = UdpSocket::bind((addr, NATPMP_PORT)).await.unwrap();
+    (socket, addr)
+}
+
+async fn receive(socket: &UdpSocket) -> (Request, SocketAddr) {
+    let mut buf = [0; 64];
+    let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+    (Request::decode(&buf[..len]).unwrap(), from)
+}
+
+fn mapped(protocol: Protocol, internal: u16, external: u16) -> Vec<u8> {
+    Response {
+        opcode: Opcode::Map(protocol),
+        result: 0,
+        epoch: 1000,
+        body: Body::Map {
+            internal,
+            external,
+            lifetime: 60,
+        },
+    }
+    .encode()
+}
+
+#[tokio::test(start_paused = true)]
+async fn maps_concurrently_over_one_socket() {
+    let gw = FakeGateway::start().await;
+    let n = client(gw.addr()).await;
+
+    let (tcp, udp, address) = tokio::join!(
+        n.map(Protocol::TCP, 8080, 0, 60),
+        n.map(Protocol::UDP, 8080, 0, 60),
+        n.public_address(),
+    );
+    let (tcp, udp) = (tcp.unwrap().public_port, udp.unwrap().public_port);
+    let mut mappings = vec![(TCP, tcp), (UDP, udp)];
+    mappings.sort();
+    assert_eq!(gw.mappings(), mappings);
+    assert_eq!(address.unwrap().ip, Some(Ipv4Addr::new(203, 0, 113, 1)));
+    // Every request got its response without being sent again.
+    assert_eq!(gw.requests().len(), 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn matches_responses_by_opcode_and_port() {
+    let (gateway, addr) = scripted_gateway().await;
+    let n = client(addr).await;
+
+    let replies = async {
+        let mut requests = vec![];
+        for _ in 0..3 {
+            requests.push(receive(&gateway).await);
+        }
+        let from = requests[0].1;
+        // Replies come in reverse order, after a late one for a mapping nobody asked for
+        // and an invalid one.
+        for reply in [
+            mapped(Protocol::TCP, 9999, 40999),
+            vec![0, 130, 0, 0, 0, 0],
+            mapped(Protocol::UDP, 8081, 40002),
+            mapped(Protocol::TCP, 8081, 40001),
+            mapped(Protocol::TCP, 8080, 40000),
+        ] {
+            gateway.send_to(&reply, from).await.unwrap();
+        }
+        requests
+    };
+    let (requests, first, second, third) = tokio::join!(
+        replies,
+        n.map(Protocol::TCP, 8080, 0, 60),
+        n.map(Protocol::TCP, 8081, 0, 60),
+        n.map(Protocol::UDP, 8081, 0, 60),
+    );
+    assert_eq!(requests.len(), 3);
+    assert_eq!(first.unwrap().public_port, 40000);
+    assert_eq!(second.unwrap().public_port, 40001);
+    assert_eq!(third.unwrap().public_port, 40002);
+}
+
+#[tokio::test(start_paused = true)]
+async fn keeps_other_requests_going_when_one_fails() {
+    let (gateway, addr) = scripted_gateway().await;
+    let n = client(addr).await;
+
+    let replies = async {
+        let (_, from) = receive(&gateway).await;
+        receive(&gateway).await;
+        // The UDP request is refused, with a response stopping after the epoch.
+        let refused = [0, 129, 0, 2, 0, 0, 3, 232];
+        gateway.send_to(&refused, from).await.unwrap();
+        // The TCP one is only answered after a retransmission.
+        receive(&gateway).await;
+        let reply = mapped(Protocol::TCP, 8080, 40000);
+        gateway.send_to(&reply, from).await.unwrap();
+    };
+    let ((), tcp, udp) = tokio::join!(
+        replies,
+        n.map(Protocol::TCP, 8080, 0, 60),
+        n.map(Protocol::UDP, 8080, 0, 60),
+    );
+    assert_eq!(tcp.unwrap().public_port, 40000);
+    let e = udp.unwrap_err();
+    assert!(e.to_string().contains("NOTAUTHORIZED"), "{}", e);
+}
+
+#[tokio::test(start_paused = true)]
+async fn ignores_responses_of_another_version() {
+    let (gateway, addr) = scripted_gateway().await;
+    let n = client(addr).await;
+
+    let replies = async {
+        let (_, from) = receive(&gateway).await;
+        // A late reply to the PCP ANNOUNCE probe comes first.
+        let mut announce = vec![2, 128, 0, 0, 0, 0, 0, 0, 0, 0, 3, 232];
+        announce.extend([0; 12]);
+        gateway.send_to(&announce, from).await.unwrap();
+        let reply = Response {
+            opcode: Opcode::PublicAddress,
+            result: 0,
+            epoch: 1000,
+            body: Body::PublicAddress(Ipv4Addr::new(203, 0, 113, 1)),
+        };
+        gateway.send_to(&reply.encode(), from).await.unwrap();
+    };
+    let ((), address) = tokio::join!(replies, n.public_address());
+    assert_eq!(address.unwrap().ip, Some(Ipv4Addr::new(203, 0, 113, 1)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn fails_when_the_gateway_does_not_support_the_version() {
+    let (gateway, addr) = scripted_gateway().await;
+    let n = client(addr).await;
+
+    let reply = async {
+        let (_, from) = receive(&gateway).await;
+        gateway.send_to(&[0, 128, 0, 1, 0, 0, 3, 232], from).await.unwrap();
+    };
+    let ((), address) = tokio::join!(reply, n.public_address());
+    let e = address.unwrap_err();
+    assert!(e.to_string().contains("UNSUPPORTEDVERSION"), "{}", e);
+}
+
+#[tokio::test(start_paused = true)]
+async fn gives_up_when_the_gateway_never_answers() {
+    let (gateway, addr) = scripted_gateway().await;
+    let n = client(addr).await;
+
+    let start = time::Instant::now();
+    let e = n.map(Protocol::TCP, 8080, 0, 60).await.unwrap_err();
+    assert!(e.to_string().contains("Mapping failed"), "{}", e);
+    // Sent 9 times, waiting twice as long each time (RFC 6886 3.1).
+    assert!(start.elapsed() >= Duration::from_millis(127_750));
+    let mut buf = [0; 64];
+    let mut sent = 0;
+    while gateway.try_recv(&mut buf).is_ok() {
+        sent += 1;
+    }
+    assert_eq!(sent, 9);
+}
+
+#[tokio::test(start_paused = true)]
+async fn maps_internal_port_0_to_any_port() {
+    let gw = FakeGateway::start().await;
+    let n = client(gw.addr()).await;
+
+    let grant = n.map(Protocol::TCP, 0, 0, 60).await.unwrap();
+    assert_eq!(grant.public_port, 40000);
+    assert_eq!(gw.mappings(), [(TCP, 40000)]);
+}
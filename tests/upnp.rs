@@ -0,0 +1,216 @@
+mod common;
+
+use common::{config, next_event};
+use common::igd::FakeIgd;
+use natpmp_setup::backend::{BackendKind, Session};
+use natpmp_setup::client::{self, release_port};
+use natpmp_setup::codec::Protocol;
+use natpmp_setup::gateway::GatewaySpec;
+use natpmp_setup::{Config, Event, PortForwarder};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::time;
+
+// Configuration of a forwarder speaking the given protocol.
+fn config_with(igd: &FakeIgd, backend: BackendKind, mappings: &[&str]) -> Config {
+    Config {
+        backend,
+        ..config(igd.addr(), mappings)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn maps_ports_with_upnp() {
+    let igd = FakeIgd::start().await;
+    let config = config_with(&igd, BackendKind::Upnp, &["both", "web:tcp:8080"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+
+    let state = forwarder.state();
+    assert_eq!(state.public_ip, Ipv4Addr::new(203, 0, 113, 1));
+    let [both, web] = &state.mappings[..] else {
+        panic!("Unexpected mappings {:?}", state.mappings);
+    };
+    // Internal port 0 is mapped to the same port as the public one.
+    let port = both.public_port(Protocol::TCP).unwrap();
+    assert!(port >= 49152, "{}", port);
+    assert_eq!(both.tcp.unwrap().private_port, port);
+    assert_eq!(both.public_port(Protocol::UDP), Some(port));
+    // Without a preferred public port, the internal one is asked for.
+    assert_eq!(web.public_port(Protocol::TCP), Some(8080));
+    let mut expected = vec![
+        ("TCP".to_owned(), port, port, 360),
+        ("TCP".to_owned(), 8080, 8080, 360),
+        ("UDP".to_owned(), port, port, 360),
+    ];
+    expected.sort();
+    assert_eq!(igd.mappings(), expected);
+    let add = igd
+        .actions()
+        .into_iter()
+        .find(|a| a.name == "AddPortMapping")
+        .unwrap();
+    assert_eq!(add.arg("NewInternalClient"), "127.0.0.1");
+    assert_eq!(add.arg("NewPortMappingDescription"), "natpmp-setup");
+
+    forwarder.shutdown().await.unwrap();
+    assert_eq!(igd.mappings(), []);
+}
+
+#[tokio::test(start_paused = true)]
+async fn falls_back_to_upnp_when_natpmp_is_closed() {
+    let igd = FakeIgd::start().await;
+    let config = config_with(&igd, BackendKind::Auto, &["web:udp:8080"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+
+    assert_eq!(forwarder.state().mappings[0].port(), Some(8080));
+    assert_eq!(igd.mappings(), [("UDP".to_owned(), 8080, 8080, 360)]);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn renews_upnp_mappings() {
+    let igd = FakeIgd::start().await;
+    let config = config_with(&igd, BackendKind::Upnp, &["web:tcp:8080:60"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    let mut events = forwarder.subscribe();
+
+    // Renewed every 30 seconds, at half the requested lease.
+    time::sleep(Duration::from_secs(615)).await;
+    let adds = igd
+        .actions()
+        .iter()
+        .filter(|a| a.name == "AddPortMapping")
+        .count();
+    assert_eq!(adds, 1 + 20);
+    assert_eq!(igd.mappings(), [("TCP".to_owned(), 8080, 8080, 60)]);
+    assert!(events.try_recv().is_err());
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn moves_to_another_port_on_a_upnp_conflict() {
+    let igd = FakeIgd::start().await;
+    igd.take_port("TCP", 40000);
+    let config = config_with(&igd, BackendKind::Upnp, &["web:tcp:8080:360:40000"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+
+    let port = forwarder.state().mappings[0].port().unwrap();
+    assert!(port >= 49152, "{}", port);
+    assert_eq!(igd.mappings(), [("TCP".to_owned(), port, 8080, 360)]);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn falls_back_to_permanent_upnp_mappings() {
+    let igd = FakeIgd::start().await;
+    igd.permanent_only();
+    let config = config_with(&igd, BackendKind::Upnp, &["web:tcp:8080"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+
+    // The mapping is still renewed and deleted like the others.
+    assert_eq!(igd.mappings(), [("TCP".to_owned(), 8080, 8080, 0)]);
+    forwarder.shutdown().await.unwrap();
+    assert_eq!(igd.mappings(), []);
+}
+
+#[tokio::test(start_paused = true)]
+async fn follows_the_upnp_external_address() {
+    let igd = FakeIgd::start().await;
+    let mut config = config_with(&igd, BackendKind::Upnp, &["web:tcp:8080"]);
+    config.public_ip_interval = Duration::from_secs(60);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    let mut events = forwarder.subscribe();
+
+    igd.set_public_ip(Ipv4Addr::new(198, 51, 100, 7));
+    let Event::PublicIpChanged { new, .. } = next_event(&mut events).await else {
+        panic!("Expected the public IP to change");
+    };
+    assert_eq!(new, Ipv4Addr::new(198, 51, 100, 7));
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn fails_to_start_when_the_igd_refuses() {
+    let igd = FakeIgd::start().await;
+    igd.fail_with(606);
+    let config = config_with(&igd, BackendKind::Upnp, &["web:tcp:8080"]);
+    let Err(e) = PortForwarder::start(config).await else {
+        panic!("Expected the forwarder to fail");
+    };
+    let message = format!("{:#}", e);
+    assert!(message.contains("UPnP error 606"), "{}", message);
+    assert!(
+        message.contains("does not support port forwarding"),
+        "{}",
+        message
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn releases_upnp_mappings_by_internal_port() {
+    let igd = FakeIgd::start().await;
+    let config = config_with(&igd, BackendKind::Upnp, &["web:tcp:8080::45000"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    assert_eq!(igd.mappings(), [("TCP".to_owned(), 45000, 8080, 360)]);
+
+    // Another client, as the release command, finds the public port on the gateway.
+    let gateway = GatewaySpec::Address(igd.addr());
+    let session = Session::default();
+    let mut n = client::connect(&gateway, None, BackendKind::Upnp, &session)
+        .await
+        .unwrap();
+    release_port(&mut n, Protocol::TCP, 8080, 0).await.unwrap();
+    assert_eq!(igd.mappings(), []);
+    let e = release_port(&mut n, Protocol::TCP, 8080, 0).await.unwrap_err();
+    assert!(
+        e.to_string().contains("no TCP mapping of internal port 8080"),
+        "{}",
+        e
+    );
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn keeps_the_upnp_mappings_of_other_programs() {
+    let igd = FakeIgd::start().await;
+    let config = config_with(&igd, BackendKind::Upnp, &["web:tcp:8080::45000"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    // Another program of this host has its own mapping.
+    igd.add_mapping("TCP", 45001, 8081, "127.0.0.1", "other");
+
+    // Releasing every mapping of this host only deletes the ones of natpmp-setup.
+    let gateway = GatewaySpec::Address(igd.addr());
+    let session = Session::default();
+    let mut n = client::connect(&gateway, None, BackendKind::Upnp, &session)
+        .await
+        .unwrap();
+    release_port(&mut n, Protocol::TCP, 0, 0).await.unwrap();
+    assert_eq!(igd.mappings(), [("TCP".to_owned(), 45001, 8081, 0)]);
+    forwarder.shutdown().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn keeps_the_upnp_epoch_across_reconnections() {
+    let igd = FakeIgd::start().await;
+    let config = config_with(&igd, BackendKind::Upnp, &["web:tcp:8080"]);
+    let forwarder = PortForwarder::start(config).await.unwrap();
+    let mut events = forwarder.subscribe();
+
+    // The renewal fails, so the forwarder connects again before recreating the mapping.
+    time::sleep(Duration::from_secs(170)).await;
+    igd.fail_with(501);
+    let Event::Lost { .. } = next_event(&mut events).await else {
+        panic!("Expected the mapping to be lost");
+    };
+    while !forwarder.state().mappings[0].is_lost() {
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    igd.fail_with(0);
+    let Event::Created { mapping, .. } = next_event(&mut events).await else {
+        panic!("Expected the mapping to be recreated");
+    };
+    // The made-up epoch does not start over, which would revalidate every mapping.
+    assert!(mapping.epoch().unwrap() >= 180, "{:?}", mapping.epoch());
+    forwarder.shutdown().await.unwrap();
+    assert_eq!(igd.mappings(), []);
+}